@@ -1,42 +1,313 @@
+use chrono::{DateTime, Utc};
 use screenshots::Screen;
+use screenshots::image::imageops::{self, FilterType};
+use screenshots::image::{DynamicImage, ImageError, Rgba, RgbaImage};
 use std::io::Cursor;
 
-use remoshot_common::ScreenshotData;
+use remoshot_common::{
+    CaptureErrorReason, CaptureOptions, ClientInfo, ImageFormat, MonitorError, MonitorInfo,
+    ScreenshotData, ScreenshotMeta,
+};
 
-pub fn capture_all_screens() -> Vec<ScreenshotData> {
+use crate::permission;
+
+pub struct CaptureOutput {
+    pub screenshots: Vec<ScreenshotData>,
+    pub errors: Vec<MonitorError>,
+}
+
+pub struct CaptureFailure {
+    pub reason: CaptureErrorReason,
+    pub detail: String,
+}
+
+struct Capture<'a> {
+    monitor: u32,
+    screen: &'a Screen,
+    image: RgbaImage,
+    captured_at: DateTime<Utc>,
+}
+
+pub fn capture_all_screens(options: &CaptureOptions) -> Result<CaptureOutput, CaptureFailure> {
     let screens = match Screen::all() {
         Ok(s) => s,
         Err(e) => {
             tracing::error!("failed to enumerate screens: {}", e);
-            return Vec::new();
+            return Err(CaptureFailure {
+                reason: CaptureErrorReason::EnumerationFailed,
+                detail: e.to_string(),
+            });
         }
     };
 
-    let mut results = Vec::new();
+    let mut captures = Vec::new();
+    let mut errors = Vec::new();
+
+    if let Some(monitors) = &options.monitors {
+        for &monitor in monitors.iter().filter(|&&m| m as usize >= screens.len()) {
+            tracing::error!("requested monitor {} does not exist", monitor);
+            errors.push(MonitorError {
+                monitor,
+                reason: CaptureErrorReason::CaptureFailed,
+                detail: format!("no monitor {monitor}, this machine has {}", screens.len()),
+            });
+        }
+    }
 
     for (i, screen) in screens.iter().enumerate() {
-        match screen.capture() {
-            Ok(img) => {
-                let mut jpeg_buf = Cursor::new(Vec::new());
-                let encoder = screenshots::image::codecs::jpeg::JpegEncoder::new_with_quality(
-                    &mut jpeg_buf,
-                    80,
-                );
-                if let Err(e) = img.write_with_encoder(encoder) {
-                    tracing::error!("failed to encode screenshot {}: {}", i, e);
-                    continue;
-                }
+        if let Some(monitors) = &options.monitors
+            && !monitors.contains(&(i as u32))
+        {
+            continue;
+        }
 
-                results.push(ScreenshotData {
-                    monitor: i as u32,
-                    data: jpeg_buf.into_inner(),
-                });
-            }
+        let captured_at = Utc::now();
+        match screen.capture() {
+            Ok(image) => captures.push(Capture {
+                monitor: i as u32,
+                screen,
+                image,
+                captured_at,
+            }),
             Err(e) => {
                 tracing::error!("failed to capture screen {}: {}", i, e);
+                errors.push(MonitorError {
+                    monitor: i as u32,
+                    reason: CaptureErrorReason::CaptureFailed,
+                    detail: e.to_string(),
+                });
             }
         }
     }
 
-    results
+    let mut results = Vec::new();
+    if options.stitch && !captures.is_empty() {
+        let monitor = captures[0].monitor;
+        let (image, meta) = stitch(captures);
+        results.extend(encode_shot(monitor, image, meta, options, &mut errors));
+    } else {
+        for capture in captures {
+            let display = &capture.screen.display_info;
+            let meta = ScreenshotMeta {
+                width: 0,
+                height: 0,
+                display_name: display.id.to_string(),
+                x: display.x,
+                y: display.y,
+                scale_factor: display.scale_factor,
+                captured_at: capture.captured_at,
+                stitched: Vec::new(),
+            };
+            results.extend(encode_shot(
+                capture.monitor,
+                capture.image,
+                meta,
+                options,
+                &mut errors,
+            ));
+        }
+    }
+
+    if results.is_empty() {
+        if !permission::has_screen_recording_permission() {
+            return Err(CaptureFailure {
+                reason: CaptureErrorReason::PermissionDenied,
+                detail: "screen recording permission is not granted".to_string(),
+            });
+        }
+        if let Some(first) = errors.first() {
+            let detail = errors
+                .iter()
+                .map(|e| format!("monitor {}: {}", e.monitor, e.detail))
+                .collect::<Vec<_>>()
+                .join("; ");
+            return Err(CaptureFailure {
+                reason: first.reason,
+                detail,
+            });
+        }
+    }
+
+    Ok(CaptureOutput {
+        screenshots: results,
+        errors,
+    })
+}
+
+/// Describes this machine and its displays, numbered as in captures.
+pub fn client_info() -> ClientInfo {
+    let monitors = match Screen::all() {
+        Ok(screens) => screens
+            .iter()
+            .enumerate()
+            .map(|(i, screen)| {
+                let display = &screen.display_info;
+                MonitorInfo {
+                    index: i as u32,
+                    name: display.id.to_string(),
+                    width: display.width,
+                    height: display.height,
+                    x: display.x,
+                    y: display.y,
+                    scale_factor: display.scale_factor,
+                    primary: display.is_primary,
+                }
+            })
+            .collect(),
+        Err(e) => {
+            tracing::warn!("failed to enumerate screens: {}", e);
+            Vec::new()
+        }
+    };
+
+    ClientInfo {
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        monitors,
+    }
+}
+
+/// Composes the captures into one image of the virtual desktop. Display
+/// positions are in desktop units, so the canvas takes the highest pixel
+/// density among the captures to keep every monitor's detail; area that no
+/// monitor covers stays black.
+fn stitch(captures: Vec<Capture>) -> (RgbaImage, ScreenshotMeta) {
+    let left = captures
+        .iter()
+        .map(|c| c.screen.display_info.x)
+        .min()
+        .unwrap_or(0);
+    let top = captures
+        .iter()
+        .map(|c| c.screen.display_info.y)
+        .min()
+        .unwrap_or(0);
+    let right = captures
+        .iter()
+        .map(|c| c.screen.display_info.x + c.screen.display_info.width as i32)
+        .max()
+        .unwrap_or(0);
+    let bottom = captures
+        .iter()
+        .map(|c| c.screen.display_info.y + c.screen.display_info.height as i32)
+        .max()
+        .unwrap_or(0);
+    let density = captures
+        .iter()
+        .map(|c| c.image.width() as f32 / c.screen.display_info.width.max(1) as f32)
+        .fold(1.0, f32::max);
+    let scale = |units: i32| (units as f32 * density).round() as i64;
+
+    let mut canvas = RgbaImage::from_pixel(
+        scale(right - left).max(1) as u32,
+        scale(bottom - top).max(1) as u32,
+        Rgba([0, 0, 0, 255]),
+    );
+    for capture in &captures {
+        let display = &capture.screen.display_info;
+        let width = scale(display.width as i32).max(1) as u32;
+        let height = scale(display.height as i32).max(1) as u32;
+        let x = scale(display.x - left);
+        let y = scale(display.y - top);
+        if capture.image.dimensions() == (width, height) {
+            imageops::replace(&mut canvas, &capture.image, x, y);
+        } else {
+            let resized = imageops::resize(&capture.image, width, height, FilterType::Triangle);
+            imageops::replace(&mut canvas, &resized, x, y);
+        }
+    }
+
+    let meta = ScreenshotMeta {
+        width: canvas.width(),
+        height: canvas.height(),
+        display_name: String::new(),
+        x: left,
+        y: top,
+        scale_factor: density,
+        captured_at: captures
+            .iter()
+            .map(|c| c.captured_at)
+            .min()
+            .unwrap_or_else(Utc::now),
+        stitched: captures.iter().map(|c| c.monitor).collect(),
+    };
+    (canvas, meta)
+}
+
+/// Encodes one image, recording a failure in `errors`.
+fn encode_shot(
+    monitor: u32,
+    image: RgbaImage,
+    mut meta: ScreenshotMeta,
+    options: &CaptureOptions,
+    errors: &mut Vec<MonitorError>,
+) -> Option<ScreenshotData> {
+    match encode(image, options) {
+        Ok((data, width, height)) => {
+            meta.width = width;
+            meta.height = height;
+            Some(ScreenshotData {
+                monitor,
+                data,
+                format: options.format,
+                meta: Some(meta),
+            })
+        }
+        Err(e) => {
+            tracing::error!("failed to encode screenshot {}: {}", monitor, e);
+            errors.push(MonitorError {
+                monitor,
+                reason: CaptureErrorReason::EncodingFailed,
+                detail: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// Returns the encoded image along with its final width and height.
+fn encode(img: RgbaImage, options: &CaptureOptions) -> Result<(Vec<u8>, u32, u32), ImageError> {
+    let mut img = DynamicImage::ImageRgba8(img);
+
+    let max_width = options.max_width.unwrap_or(u32::MAX);
+    let max_height = options.max_height.unwrap_or(u32::MAX);
+    if img.width() > max_width || img.height() > max_height {
+        img = img.resize(max_width, max_height, FilterType::Triangle);
+    }
+
+    if options.grayscale {
+        img = DynamicImage::ImageLuma8(img.to_luma8());
+    }
+
+    let (width, height) = (img.width(), img.height());
+    let mut buf = Cursor::new(Vec::new());
+    match options.format {
+        ImageFormat::Jpeg => {
+            let img = match img {
+                DynamicImage::ImageLuma8(_) => img,
+                _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+            };
+            let encoder = screenshots::image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut buf,
+                options.quality.unwrap_or(80),
+            );
+            img.write_with_encoder(encoder)?;
+        }
+        ImageFormat::Png => {
+            let encoder = screenshots::image::codecs::png::PngEncoder::new(&mut buf);
+            img.write_with_encoder(encoder)?;
+        }
+        ImageFormat::Webp => {
+            let img = match img {
+                DynamicImage::ImageLuma8(_) => DynamicImage::ImageRgb8(img.to_rgb8()),
+                _ => img,
+            };
+            let encoder = screenshots::image::codecs::webp::WebPEncoder::new_lossless(&mut buf);
+            img.write_with_encoder(encoder)?;
+        }
+    }
+
+    Ok((buf.into_inner(), width, height))
 }
@@ -7,7 +7,55 @@ use std::path::PathBuf;
 pub struct Config {
     pub server_addr: String,
     pub machine_name: String,
+    /// Random ID identifying this machine to the server across restarts and
+    /// renames, generated once.
+    #[serde(default)]
+    pub machine_id: String,
     pub secret_key: String,
+    /// One-time token for enrolling with the server; cleared once the
+    /// server has issued a client key.
+    #[serde(default)]
+    pub enrollment_token: String,
+    /// Key issued to this client on enrollment, preferred over the shared
+    /// secret key.
+    #[serde(default)]
+    pub client_key: String,
+    /// PEM file with the CA certificates to trust for `wss://` instead of
+    /// the system roots.
+    #[serde(default)]
+    pub ca_bundle: String,
+    /// SHA-256 fingerprint of the only server certificate to accept.
+    #[serde(default)]
+    pub pinned_fingerprint: String,
+    /// Pin the certificate presented on the first successful connection.
+    #[serde(default)]
+    pub trust_on_first_use: bool,
+    /// `http://` or `socks5://` proxy URL; when empty the proxy environment
+    /// variables are used.
+    #[serde(default)]
+    pub proxy: String,
+    /// Overrides the credentials in the proxy URL.
+    #[serde(default)]
+    pub proxy_username: String,
+    #[serde(default)]
+    pub proxy_password: String,
+}
+
+pub enum Credential {
+    Key(String),
+    EnrollmentToken(String),
+}
+
+impl Config {
+    pub fn credential(&self) -> Credential {
+        if !self.enrollment_token.is_empty() {
+            Credential::EnrollmentToken(self.enrollment_token.clone())
+        } else if !self.client_key.is_empty() {
+            Credential::Key(self.client_key.clone())
+        } else {
+            Credential::Key(self.secret_key.clone())
+        }
+    }
 }
 
 impl Default for Config {
@@ -15,11 +63,24 @@ impl Default for Config {
         Self {
             server_addr: "ws://127.0.0.1:8283/ws".to_string(),
             machine_name: whoami().unwrap_or_else(|| "unknown".to_string()),
+            machine_id: generate_machine_id(),
             secret_key: String::new(),
+            enrollment_token: String::new(),
+            client_key: String::new(),
+            ca_bundle: String::new(),
+            pinned_fingerprint: String::new(),
+            trust_on_first_use: false,
+            proxy: String::new(),
+            proxy_username: String::new(),
+            proxy_password: String::new(),
         }
     }
 }
 
+fn generate_machine_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
 fn whoami() -> Option<String> {
     std::env::var("COMPUTERNAME")
         .or_else(|_| std::env::var("HOSTNAME"))
@@ -36,7 +97,13 @@ fn config_path() -> PathBuf {
 pub fn load() -> Option<Config> {
     let path = config_path();
     let data = fs::read_to_string(path).ok()?;
-    serde_json::from_str(&data).ok()
+    let mut config: Config = serde_json::from_str(&data).ok()?;
+    // Settings saved by older versions have no machine ID yet.
+    if config.machine_id.is_empty() {
+        config.machine_id = generate_machine_id();
+        save(&config);
+    }
+    Some(config)
 }
 
 pub fn save(config: &Config) {
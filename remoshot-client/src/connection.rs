@@ -1,17 +1,24 @@
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use std::sync::{Arc, Mutex};
 use tokio::sync::{mpsc, watch};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::capture;
+use crate::config::{self, Config, Credential};
+use crate::{proxy, tls};
+
+/// How long the server gets to prove that it knows the key after our auth
+/// response, so that a server that never does is not mistaken for a quiet one.
+const AUTH_CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
 
 pub async fn run(
-    server_addr: String,
-    machine_name: String,
-    secret_key: String,
+    config: Arc<Mutex<Config>>,
     status_tx: mpsc::UnboundedSender<ConnectionStatus>,
     mut cancel_rx: watch::Receiver<bool>,
 ) {
     let mut attempt: u32 = 0;
+    let mut reconnect_after = None;
     let _ = rustls::crypto::ring::default_provider().install_default();
 
     loop {
@@ -19,21 +26,23 @@ pub async fn run(
             return;
         }
 
+        let cfg = config.lock().unwrap().clone();
+        let server_addr = cfg.server_addr.clone();
         let _ = status_tx.send(ConnectionStatus::Connecting);
         tracing::info!("connecting to {}...", server_addr);
 
-        match tokio_tungstenite::connect_async(&server_addr).await {
-            Ok((ws_stream, _)) => {
+        match connect(&cfg).await {
+            Ok((ws_stream, first_use_fingerprint)) => {
                 attempt = 0;
-                let _ = status_tx.send(ConnectionStatus::Connected);
                 tracing::info!("connected to {}", server_addr);
 
                 if handle_connection(
                     ws_stream,
-                    machine_name.clone(),
-                    secret_key.clone(),
+                    &config,
+                    first_use_fingerprint,
                     &status_tx,
                     &mut cancel_rx,
+                    &mut reconnect_after,
                 )
                 .await
                 {
@@ -46,9 +55,13 @@ pub async fn run(
             }
         }
 
-        let base_delay = std::cmp::min(2u64.saturating_pow(attempt), 60);
-        let jitter = rand::random::<f64>() * base_delay as f64 * 0.3;
-        let delay = std::time::Duration::from_secs_f64(base_delay as f64 + jitter);
+        // A server that shut down tells us when it expects to be back.
+        let base_delay = match reconnect_after.take() {
+            Some(delay) => delay.as_secs_f64(),
+            None => std::cmp::min(2u64.saturating_pow(attempt), 60) as f64,
+        };
+        let jitter = rand::random::<f64>() * base_delay * 0.3;
+        let delay = std::time::Duration::from_secs_f64(base_delay + jitter);
         tracing::info!("reconnecting in {:.1}s...", delay.as_secs_f64());
 
         tokio::select! {
@@ -61,34 +74,79 @@ pub async fn run(
     }
 }
 
-async fn handle_connection(
-    ws_stream: tokio_tungstenite::WebSocketStream<
-        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-    >,
-    machine_name: String,
-    secret_key: String,
-    status_tx: &mpsc::UnboundedSender<ConnectionStatus>,
-    cancel_rx: &mut watch::Receiver<bool>,
-) -> bool {
-    let (mut ws_tx, mut ws_rx) = ws_stream.split();
+/// Opens the WebSocket, through a proxy and with the configured TLS trust
+/// when needed. Also returns the certificate fingerprint to pin if the
+/// certificate was trusted on first use.
+async fn connect(cfg: &Config) -> Result<(WsStream, Option<String>), String> {
+    let url = url::Url::parse(&cfg.server_addr)
+        .map_err(|e| format!("invalid server address {}: {e}", cfg.server_addr))?;
+
+    let (connector, first_use) = if url.scheme() == "wss" {
+        let tls = tls::connector(cfg).map_err(|e| format!("invalid TLS settings: {e}"))?;
+        (Some(tls.connector), tls.first_use)
+    } else {
+        (None, None)
+    };
 
-    let nonce = loop {
+    let (ws_stream, _) = match proxy::for_server(cfg, &url)? {
+        Some(proxy) => {
+            let host = url
+                .host_str()
+                .ok_or("server address has no host")?
+                .trim_matches(['[', ']']);
+            let port = url
+                .port_or_known_default()
+                .ok_or("server address has no port")?;
+            tracing::info!("connecting through proxy {}", proxy);
+            let stream = proxy.connect(host, port).await?;
+            tokio_tungstenite::client_async_tls_with_config(
+                cfg.server_addr.as_str(),
+                stream,
+                None,
+                connector,
+            )
+            .await
+        }
+        None => {
+            tokio_tungstenite::connect_async_tls_with_config(
+                cfg.server_addr.as_str(),
+                None,
+                false,
+                connector,
+            )
+            .await
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    let fingerprint = first_use.and_then(|seen| seen.lock().unwrap().take());
+    Ok((ws_stream, fingerprint))
+}
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+enum AuthStep {
+    Message(remoshot_common::ServerMessage),
+    Cancelled,
+    Closed,
+}
+
+async fn next_auth_message(
+    ws_rx: &mut SplitStream<WsStream>,
+    cancel_rx: &mut watch::Receiver<bool>,
+) -> AuthStep {
+    loop {
         tokio::select! {
             _ = cancel_rx.changed() => {
                 tracing::info!("connection cancelled during auth");
-                return true;
+                return AuthStep::Cancelled;
             }
             msg_opt = ws_rx.next() => {
                 match msg_opt {
                     Some(Ok(Message::Text(text))) => {
                         match serde_json::from_str::<remoshot_common::ServerMessage>(&text) {
-                            Ok(remoshot_common::ServerMessage::AuthChallenge { nonce }) => {
-                                break nonce;
-                            }
-                            Ok(msg) => {
-                                tracing::warn!("unexpected message during auth: {:?}", msg);
-                                continue;
-                            }
+                            Ok(msg) => return AuthStep::Message(msg),
                             Err(e) => {
                                 tracing::warn!("invalid auth message: {}", e);
                                 continue;
@@ -97,23 +155,114 @@ async fn handle_connection(
                     }
                     Some(Ok(Message::Close(_))) => {
                         tracing::info!("server closed connection during auth");
-                        return false;
+                        return AuthStep::Closed;
                     }
                     Some(Err(e)) => {
                         tracing::error!("ws error during auth: {}", e);
-                        return false;
+                        return AuthStep::Closed;
                     }
-                    None => return false,
+                    None => return AuthStep::Closed,
                     _ => continue,
                 }
             }
         }
+    }
+}
+
+async fn handle_connection(
+    ws_stream: WsStream,
+    config: &Arc<Mutex<Config>>,
+    first_use_fingerprint: Option<String>,
+    status_tx: &mpsc::UnboundedSender<ConnectionStatus>,
+    cancel_rx: &mut watch::Receiver<bool>,
+    reconnect_after: &mut Option<std::time::Duration>,
+) -> bool {
+    let (mut ws_tx, mut ws_rx) = ws_stream.split();
+    let (machine_name, machine_id, credential) = {
+        let cfg = config.lock().unwrap();
+        (
+            cfg.machine_name.clone(),
+            cfg.machine_id.clone(),
+            cfg.credential(),
+        )
+    };
+
+    // Stays `None` for servers that predate the hello exchange.
+    let mut server_capabilities = None;
+    let (nonce, server_capabilities) = loop {
+        match next_auth_message(&mut ws_rx, cancel_rx).await {
+            AuthStep::Message(remoshot_common::ServerMessage::Hello(hello)) => {
+                tracing::info!(
+                    "server app version {}, protocol version {}",
+                    hello.app_version,
+                    hello.protocol_version
+                );
+                if !hello.is_compatible() {
+                    tracing::error!(
+                        "server speaks protocol version {}, which this client no longer supports",
+                        hello.protocol_version
+                    );
+                    let _ = status_tx.send(ConnectionStatus::Rejected(
+                        remoshot_common::AuthRejectReason::UnsupportedVersion,
+                    ));
+                    return false;
+                }
+                server_capabilities = Some(hello.capabilities);
+            }
+            AuthStep::Message(remoshot_common::ServerMessage::AuthChallenge { nonce }) => {
+                let Some(capabilities) = server_capabilities else {
+                    tracing::error!(
+                        "server sent no hello, it speaks protocol version 1, which this client no longer supports"
+                    );
+                    let _ = status_tx.send(ConnectionStatus::Rejected(
+                        remoshot_common::AuthRejectReason::UnsupportedVersion,
+                    ));
+                    return false;
+                };
+                break (nonce, capabilities);
+            }
+            AuthStep::Message(msg) => {
+                tracing::warn!("unexpected message during auth: {:?}", msg);
+            }
+            AuthStep::Cancelled => return true,
+            AuthStep::Closed => return false,
+        }
+    };
+
+    let hello = remoshot_common::ClientMessage::Hello(remoshot_common::Hello::new(
+        env!("CARGO_PKG_VERSION"),
+        remoshot_common::Capabilities::all(),
+    ));
+    let msg = serde_json::to_string(&hello).unwrap();
+    if let Err(e) = ws_tx.send(Message::Text(msg.into())).await {
+        tracing::error!("failed to send hello: {}", e);
+        let _ = status_tx.send(ConnectionStatus::Disconnected);
+        return false;
+    }
+
+    let secret_key = match credential {
+        Credential::Key(key) => key,
+        Credential::EnrollmentToken(token) => {
+            return enroll(
+                &mut ws_tx,
+                &mut ws_rx,
+                config,
+                machine_name,
+                token,
+                status_tx,
+                cancel_rx,
+            )
+            .await;
+        }
     };
 
-    let hmac = remoshot_common::compute_hmac(&secret_key, &nonce);
+    let client_nonce = format!("{:032x}", rand::random::<u128>());
+    let hmac = remoshot_common::compute_client_hmac(&secret_key, &nonce);
     let auth_response = remoshot_common::ClientMessage::AuthResponse {
         name: machine_name.clone(),
         hmac,
+        client_nonce: Some(client_nonce.clone()),
+        machine_id: Some(machine_id),
     };
     let msg = serde_json::to_string(&auth_response).unwrap();
     if let Err(e) = ws_tx.send(Message::Text(msg.into())).await {
@@ -121,7 +270,68 @@ async fn handle_connection(
         let _ = status_tx.send(ConnectionStatus::Disconnected);
         return false;
     }
+
+    // Do not serve any request until the server has proven that it knows
+    // the secret key too.
+    let deadline = tokio::time::Instant::now() + AUTH_CONFIRM_TIMEOUT;
+    loop {
+        let Ok(step) =
+            tokio::time::timeout_at(deadline, next_auth_message(&mut ws_rx, cancel_rx)).await
+        else {
+            tracing::error!(
+                "server did not prove knowledge of the secret key within {:?}",
+                AUTH_CONFIRM_TIMEOUT
+            );
+            let _ = status_tx.send(ConnectionStatus::Unverified);
+            return false;
+        };
+        match step {
+            AuthStep::Message(remoshot_common::ServerMessage::AuthConfirm { hmac }) => {
+                if remoshot_common::verify_server_hmac(&secret_key, &nonce, &client_nonce, &hmac) {
+                    break;
+                }
+                tracing::error!("server failed to prove knowledge of the secret key");
+                let _ = status_tx.send(ConnectionStatus::Unverified);
+                return false;
+            }
+            AuthStep::Message(remoshot_common::ServerMessage::AuthRejected { reason }) => {
+                tracing::error!("server rejected authentication: {:?}", reason);
+                let _ = status_tx.send(ConnectionStatus::Rejected(reason));
+                return false;
+            }
+            AuthStep::Message(msg) => {
+                tracing::warn!("ignoring message before server authentication: {:?}", msg);
+            }
+            AuthStep::Cancelled => return true,
+            AuthStep::Closed => {
+                let _ = status_tx.send(ConnectionStatus::Disconnected);
+                return false;
+            }
+        }
+    }
     tracing::info!("authenticated as '{}'", machine_name);
+    let _ = status_tx.send(ConnectionStatus::Connected);
+    let mut handshake = Handshake {
+        secret_key,
+        server_nonce: nonce,
+        client_nonce,
+    };
+
+    // Only pin a first-use certificate once the server has also proven that
+    // it knows our key.
+    if let Some(fingerprint) = first_use_fingerprint {
+        let mut cfg = config.lock().unwrap();
+        cfg.pinned_fingerprint = fingerprint;
+        config::save(&cfg);
+        tracing::info!("pinned server certificate {}", cfg.pinned_fingerprint);
+    }
+
+    // Displays can be plugged in or rearranged at any time, so look for
+    // changes periodically and report them to servers that want to know.
+    let report_info = server_capabilities.has_feature(remoshot_common::features::CLIENT_INFO);
+    let mut reported_info = None;
+    let mut info_interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    info_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     let (ping_tx, mut ping_rx) = mpsc::unbounded_channel();
 
@@ -151,8 +361,13 @@ async fn handle_connection(
                     break;
                 }
             }
+            _ = info_interval.tick(), if report_info => {
+                if !report_client_info(&mut ws_tx, &mut reported_info).await {
+                    break;
+                }
+            }
             msg_opt = ws_rx.next() => {
-                if !handle_message(msg_opt, &mut ws_tx).await {
+                if !handle_message(msg_opt, &mut ws_tx, config, &mut handshake, reconnect_after).await {
                     break;
                 }
             }
@@ -165,44 +380,197 @@ async fn handle_connection(
     false
 }
 
+/// Trades the enrollment token for a client key, which the server only
+/// accepts once an administrator approved this client.
+async fn enroll(
+    ws_tx: &mut SplitSink<WsStream, Message>,
+    ws_rx: &mut SplitStream<WsStream>,
+    config: &Arc<Mutex<Config>>,
+    machine_name: String,
+    token: String,
+    status_tx: &mpsc::UnboundedSender<ConnectionStatus>,
+    cancel_rx: &mut watch::Receiver<bool>,
+) -> bool {
+    let enroll = remoshot_common::ClientMessage::Enroll {
+        name: machine_name.clone(),
+        token,
+    };
+    let msg = serde_json::to_string(&enroll).unwrap();
+    if let Err(e) = ws_tx.send(Message::Text(msg.into())).await {
+        tracing::error!("failed to send enrollment request: {}", e);
+        let _ = status_tx.send(ConnectionStatus::Disconnected);
+        return false;
+    }
+
+    loop {
+        match next_auth_message(ws_rx, cancel_rx).await {
+            AuthStep::Message(remoshot_common::ServerMessage::Enrolled { key }) => {
+                let mut cfg = config.lock().unwrap();
+                cfg.client_key = key;
+                cfg.enrollment_token.clear();
+                config::save(&cfg);
+                tracing::info!("enrolled as '{}', waiting for approval", machine_name);
+                let _ = status_tx.send(ConnectionStatus::Rejected(
+                    remoshot_common::AuthRejectReason::PendingApproval,
+                ));
+                return false;
+            }
+            AuthStep::Message(remoshot_common::ServerMessage::AuthRejected { reason }) => {
+                tracing::error!("server rejected enrollment: {:?}", reason);
+                let _ = status_tx.send(ConnectionStatus::Rejected(reason));
+                return false;
+            }
+            AuthStep::Message(msg) => {
+                tracing::warn!("unexpected message during enrollment: {:?}", msg);
+            }
+            AuthStep::Cancelled => return true,
+            AuthStep::Closed => {
+                let _ = status_tx.send(ConnectionStatus::Disconnected);
+                return false;
+            }
+        }
+    }
+}
+
+/// Sends the client info unless it equals the one reported last.
+async fn report_client_info(
+    ws_tx: &mut SplitSink<WsStream, Message>,
+    reported: &mut Option<remoshot_common::ClientInfo>,
+) -> bool {
+    let Ok(info) = tokio::task::spawn_blocking(capture::client_info).await else {
+        return true;
+    };
+    if reported.as_ref() == Some(&info) {
+        return true;
+    }
+
+    tracing::info!(
+        "reporting {} monitors on {} ({})",
+        info.monitors.len(),
+        info.hostname,
+        info.os
+    );
+    let msg = remoshot_common::ClientMessage::ClientInfo(info.clone());
+    if let Err(e) = ws_tx
+        .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
+        .await
+    {
+        tracing::error!("failed to send client info: {}", e);
+        return false;
+    }
+    *reported = Some(info);
+    true
+}
+
+/// What a completed handshake established, needed to unwrap secret key
+/// updates.
+struct Handshake {
+    secret_key: String,
+    server_nonce: String,
+    client_nonce: String,
+}
+
 async fn handle_message(
     msg_opt: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>,
-    ws_tx: &mut futures_util::stream::SplitSink<
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-        Message,
-    >,
+    ws_tx: &mut SplitSink<WsStream, Message>,
+    config: &Arc<Mutex<Config>>,
+    handshake: &mut Handshake,
+    reconnect_after: &mut Option<std::time::Duration>,
 ) -> bool {
     match msg_opt {
         Some(Ok(Message::Text(text))) => {
             match serde_json::from_str::<remoshot_common::ServerMessage>(&text) {
-                Ok(remoshot_common::ServerMessage::ScreenshotRequest { request_id }) => {
-                    tracing::info!("screenshot request: {}", request_id);
+                Ok(remoshot_common::ServerMessage::ScreenshotRequest {
+                    request_id,
+                    options,
+                }) => {
+                    tracing::info!("screenshot request: {} ({:?})", request_id, options);
 
-                    let screenshots = tokio::task::spawn_blocking(capture::capture_all_screens)
-                        .await
-                        .unwrap_or_default();
+                    let captured =
+                        tokio::task::spawn_blocking(move || capture::capture_all_screens(&options))
+                            .await
+                            .unwrap_or_else(|e| {
+                                Err(capture::CaptureFailure {
+                                    reason: remoshot_common::CaptureErrorReason::CaptureFailed,
+                                    detail: format!("capture task failed: {e}"),
+                                })
+                            });
 
-                    tracing::info!(
-                        "captured {} screenshots for request {}",
-                        screenshots.len(),
-                        request_id
-                    );
+                    let msg = match captured {
+                        Ok(output) => {
+                            tracing::info!(
+                                "captured {} screenshots for request {}",
+                                output.screenshots.len(),
+                                request_id
+                            );
+
+                            let response = remoshot_common::ClientMessage::ScreenshotResponse {
+                                request_id: request_id.clone(),
+                                screenshots: output.screenshots,
+                                errors: output.errors,
+                            };
+                            // Field names let servers skip metadata they do not know.
+                            Message::Binary(rmp_serde::to_vec_named(&response).unwrap().into())
+                        }
+                        Err(failure) => {
+                            tracing::error!(
+                                "capture failed for request {}: {:?}: {}",
+                                request_id,
+                                failure.reason,
+                                failure.detail
+                            );
 
-                    let response = remoshot_common::ClientMessage::ScreenshotResponse {
-                        request_id: request_id.clone(),
-                        screenshots,
+                            let response = remoshot_common::ClientMessage::ScreenshotError {
+                                request_id: request_id.clone(),
+                                reason: failure.reason,
+                                detail: failure.detail,
+                            };
+                            Message::Text(serde_json::to_string(&response).unwrap().into())
+                        }
                     };
-                    let msg = rmp_serde::to_vec(&response).unwrap();
-                    if let Err(e) = ws_tx.send(Message::Binary(msg.into())).await {
+                    if let Err(e) = ws_tx.send(msg).await {
                         tracing::error!("failed to send response: {}", e);
                         return false;
                     }
                     tracing::info!("screenshot response sent for request {}", request_id);
                 }
-                Ok(remoshot_common::ServerMessage::AuthChallenge { .. }) => {
-                    tracing::warn!("unexpected auth challenge after authentication");
+                Ok(remoshot_common::ServerMessage::SecretUpdate { wrapped_key, hmac }) => {
+                    let mut cfg = config.lock().unwrap();
+                    if !cfg.client_key.is_empty() {
+                        tracing::warn!("ignoring secret key update, this client uses its own key");
+                    } else if let Some(secret_key) = remoshot_common::unwrap_secret(
+                        &handshake.secret_key,
+                        &handshake.server_nonce,
+                        &handshake.client_nonce,
+                        &wrapped_key,
+                        &hmac,
+                    ) {
+                        cfg.secret_key = secret_key.clone();
+                        config::save(&cfg);
+                        handshake.secret_key = secret_key;
+                        tracing::info!("server rotated the secret key, settings updated");
+                    } else {
+                        tracing::warn!(
+                            "ignoring secret key update that was not wrapped with our key"
+                        );
+                    }
+                }
+                Ok(remoshot_common::ServerMessage::Shutdown { reconnect_after_ms }) => {
+                    tracing::info!(
+                        "server is shutting down, reconnecting in {}ms",
+                        reconnect_after_ms
+                    );
+                    *reconnect_after = Some(std::time::Duration::from_millis(reconnect_after_ms));
+                    return false;
+                }
+                Ok(
+                    remoshot_common::ServerMessage::Hello(_)
+                    | remoshot_common::ServerMessage::AuthChallenge { .. }
+                    | remoshot_common::ServerMessage::AuthConfirm { .. }
+                    | remoshot_common::ServerMessage::AuthRejected { .. }
+                    | remoshot_common::ServerMessage::Enrolled { .. },
+                ) => {
+                    tracing::warn!("unexpected auth message after authentication");
                 }
                 Err(e) => {
                     tracing::warn!("unknown message: {}", e);
@@ -232,4 +600,7 @@ pub enum ConnectionStatus {
     Connecting,
     Connected,
     Disconnected,
+    /// The server did not prove that it knows the key.
+    Unverified,
+    Rejected(remoshot_common::AuthRejectReason),
 }
@@ -5,7 +5,9 @@ mod config;
 mod connection;
 mod log_buffer;
 mod permission;
+mod proxy;
 mod single_instance;
+mod tls;
 mod tray;
 
 slint::include_modules!();
@@ -1,9 +1,8 @@
 #[cfg(target_os = "macos")]
-pub fn check_and_request_screen_recording_permission() -> bool {
+pub fn has_screen_recording_permission() -> bool {
     use screenshots::Screen;
-    use std::process::Command;
 
-    let has_permission = match Screen::all() {
+    match Screen::all() {
         Ok(screens) => {
             if screens.is_empty() {
                 false
@@ -12,9 +11,19 @@ pub fn check_and_request_screen_recording_permission() -> bool {
             }
         }
         Err(_) => false,
-    };
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn has_screen_recording_permission() -> bool {
+    true
+}
+
+#[cfg(target_os = "macos")]
+pub fn check_and_request_screen_recording_permission() -> bool {
+    use std::process::Command;
 
-    if !has_permission {
+    if !has_screen_recording_permission() {
         tracing::warn!("no screen recording permission detected, opening system preferences");
 
         let _ = Command::new("open")
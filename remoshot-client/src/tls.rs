@@ -0,0 +1,169 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use tokio_tungstenite::Connector;
+
+use crate::config::Config;
+
+pub struct Tls {
+    pub connector: Connector,
+    /// Filled in with the fingerprint of the server certificate during the
+    /// handshake when the certificate is trusted on first use.
+    pub first_use: Option<Arc<Mutex<Option<String>>>>,
+}
+
+/// Builds the TLS connector for `wss://` servers from the settings: a
+/// pinned fingerprint (or trust on first use) takes precedence over the
+/// custom CA bundle, which in turn replaces the system roots.
+pub fn connector(config: &Config) -> Result<Tls, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let pin = normalize_fingerprint(&config.pinned_fingerprint);
+    let mut first_use = None;
+    let client_config = if !pin.is_empty() || config.trust_on_first_use {
+        let pin = if pin.is_empty() {
+            let observed = Arc::new(Mutex::new(None));
+            first_use = Some(observed.clone());
+            Pin::FirstUse(observed)
+        } else {
+            Pin::Fingerprint(pin)
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier { pin, provider }))
+            .with_no_client_auth()
+    } else {
+        builder
+            .with_root_certificates(root_store(&config.ca_bundle)?)
+            .with_no_client_auth()
+    };
+
+    Ok(Tls {
+        connector: Connector::Rustls(Arc::new(client_config)),
+        first_use,
+    })
+}
+
+fn root_store(ca_bundle: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    if ca_bundle.is_empty() {
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            tracing::warn!("failed to load system certificate: {}", e);
+        }
+        roots.add_parsable_certificates(native.certs);
+    } else {
+        for cert in CertificateDer::pem_file_iter(ca_bundle)
+            .map_err(|e| format!("cannot read CA bundle {ca_bundle}: {e}"))?
+        {
+            let cert = cert.map_err(|e| format!("invalid certificate in {ca_bundle}: {e}"))?;
+            roots
+                .add(cert)
+                .map_err(|e| format!("invalid CA certificate in {ca_bundle}: {e}"))?;
+        }
+    }
+
+    if roots.is_empty() {
+        return Err("no trusted CA certificates".to_string());
+    }
+    Ok(roots)
+}
+
+/// SHA-256 fingerprint of a certificate as colon separated upper-case hex,
+/// the format the server prints on startup.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Accepts fingerprints with or without colons and in either case.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[derive(Debug)]
+enum Pin {
+    Fingerprint(String),
+    FirstUse(Arc<Mutex<Option<String>>>),
+}
+
+/// Trusts exactly the certificate with the pinned fingerprint, regardless of
+/// who issued it, so that self-signed servers can be used.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: Pin,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = fingerprint(end_entity);
+        match &self.pin {
+            Pin::Fingerprint(pin) if *pin != normalize_fingerprint(&presented) => {
+                Err(rustls::Error::General(format!(
+                    "server certificate {presented} does not match the pinned fingerprint"
+                )))
+            }
+            Pin::Fingerprint(_) => Ok(ServerCertVerified::assertion()),
+            Pin::FirstUse(observed) => {
+                *observed.lock().unwrap() = Some(presented);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -1,3 +1,4 @@
+use remoshot_common::AuthRejectReason;
 use slint::ComponentHandle;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
@@ -100,15 +101,36 @@ fn show_settings_sync(config: &Arc<Mutex<Config>>) {
     win.set_server_addr(current.server_addr.as_str().into());
     win.set_machine_name(current.machine_name.as_str().into());
     win.set_secret_key(current.secret_key.as_str().into());
+    win.set_enrollment_token(current.enrollment_token.as_str().into());
+    win.set_ca_bundle(current.ca_bundle.as_str().into());
+    win.set_pinned_fingerprint(current.pinned_fingerprint.as_str().into());
+    win.set_trust_on_first_use(current.trust_on_first_use);
+    win.set_proxy(current.proxy.as_str().into());
+    win.set_proxy_username(current.proxy_username.as_str().into());
+    win.set_proxy_password(current.proxy_password.as_str().into());
 
     let win_weak = win.as_weak();
     let cfg = config.clone();
     win.on_save(move || {
         let w = win_weak.unwrap();
+        // Not editable in the window; kept from the current settings.
+        let (client_key, machine_id) = {
+            let current = cfg.lock().unwrap();
+            (current.client_key.clone(), current.machine_id.clone())
+        };
         let new_config = Config {
             server_addr: w.get_server_addr().to_string(),
-            machine_name: w.get_machine_name().to_string(),
+            machine_name: w.get_machine_name().trim().to_string(),
+            machine_id,
             secret_key: w.get_secret_key().to_string(),
+            enrollment_token: w.get_enrollment_token().trim().to_string(),
+            client_key,
+            ca_bundle: w.get_ca_bundle().trim().to_string(),
+            pinned_fingerprint: w.get_pinned_fingerprint().trim().to_string(),
+            trust_on_first_use: w.get_trust_on_first_use(),
+            proxy: w.get_proxy().trim().to_string(),
+            proxy_username: w.get_proxy_username().to_string(),
+            proxy_password: w.get_proxy_password().to_string(),
         };
         config::save(&new_config);
         *cfg.lock().unwrap() = new_config;
@@ -143,6 +165,13 @@ fn show_settings_window(
     win.set_server_addr(current.server_addr.as_str().into());
     win.set_machine_name(current.machine_name.as_str().into());
     win.set_secret_key(current.secret_key.as_str().into());
+    win.set_enrollment_token(current.enrollment_token.as_str().into());
+    win.set_ca_bundle(current.ca_bundle.as_str().into());
+    win.set_pinned_fingerprint(current.pinned_fingerprint.as_str().into());
+    win.set_trust_on_first_use(current.trust_on_first_use);
+    win.set_proxy(current.proxy.as_str().into());
+    win.set_proxy_username(current.proxy_username.as_str().into());
+    win.set_proxy_password(current.proxy_password.as_str().into());
 
     let win_weak = win.as_weak();
     let cfg = config.clone();
@@ -152,10 +181,24 @@ fn show_settings_window(
     let item_status_c = item_status.clone();
     win.on_save(move || {
         let w = win_weak.unwrap();
+        // Not editable in the window; kept from the current settings.
+        let (client_key, machine_id) = {
+            let current = cfg.lock().unwrap();
+            (current.client_key.clone(), current.machine_id.clone())
+        };
         let new_config = Config {
             server_addr: w.get_server_addr().to_string(),
-            machine_name: w.get_machine_name().to_string(),
+            machine_name: w.get_machine_name().trim().to_string(),
+            machine_id,
             secret_key: w.get_secret_key().to_string(),
+            enrollment_token: w.get_enrollment_token().trim().to_string(),
+            client_key,
+            ca_bundle: w.get_ca_bundle().trim().to_string(),
+            pinned_fingerprint: w.get_pinned_fingerprint().trim().to_string(),
+            trust_on_first_use: w.get_trust_on_first_use(),
+            proxy: w.get_proxy().trim().to_string(),
+            proxy_username: w.get_proxy_username().to_string(),
+            proxy_password: w.get_proxy_password().to_string(),
         };
         config::save(&new_config);
         *cfg.lock().unwrap() = new_config;
@@ -230,7 +273,7 @@ fn start_connection(
     let (cancel_tx, cancel_rx) = tokio::sync::watch::channel(false);
     *cancel_token.lock().unwrap() = Some(cancel_tx);
 
-    let cfg = config.lock().unwrap().clone();
+    let cfg = config.clone();
     let status = status_text.clone();
 
     rt.spawn(async move {
@@ -243,19 +286,27 @@ fn start_connection(
                     ConnectionStatus::Connecting => "Connecting...",
                     ConnectionStatus::Connected => "Connected",
                     ConnectionStatus::Disconnected => "Disconnected",
+                    ConnectionStatus::Unverified => "Server not verified",
+                    ConnectionStatus::Rejected(AuthRejectReason::PendingApproval) => {
+                        "Waiting for approval"
+                    }
+                    ConnectionStatus::Rejected(AuthRejectReason::Revoked) => "Access revoked",
+                    ConnectionStatus::Rejected(AuthRejectReason::NameInUse) => {
+                        "Name already in use"
+                    }
+                    ConnectionStatus::Rejected(AuthRejectReason::InvalidName) => {
+                        "Invalid machine name"
+                    }
+                    ConnectionStatus::Rejected(AuthRejectReason::UnsupportedVersion) => {
+                        "Incompatible version"
+                    }
+                    ConnectionStatus::Rejected(_) => "Rejected by server",
                 };
                 *status_c.lock().unwrap() = text.to_string();
             }
         });
 
-        connection::run(
-            cfg.server_addr,
-            cfg.machine_name,
-            cfg.secret_key,
-            status_tx,
-            cancel_rx,
-        )
-        .await;
+        connection::run(cfg, status_tx, cancel_rx).await;
     });
 }
 
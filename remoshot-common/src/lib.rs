@@ -1,33 +1,346 @@
+use chrono::{DateTime, Utc};
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Version of the protocol spoken by this build. Peers that send no
+/// [`Hello`] predate the hello exchange and speak version 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this build still talks to. Version 1 lacks the
+/// mutual authentication, so its peers cannot prove who they are.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features a peer can announce in its [`Hello`].
+pub mod features {
+    /// Honors [`crate::CaptureOptions`] in screenshot requests.
+    pub const CAPTURE_OPTIONS: &str = "capture_options";
+    /// Reports failures of single monitors in [`crate::MonitorError`]s.
+    pub const MONITOR_ERRORS: &str = "monitor_errors";
+    /// Stores secret keys pushed with [`crate::ServerMessage::SecretUpdate`].
+    pub const SECRET_UPDATE: &str = "secret_update";
+    /// Reconnects as told by [`crate::ServerMessage::Shutdown`].
+    pub const SHUTDOWN: &str = "shutdown";
+    /// Sends a stable machine ID when authenticating.
+    pub const MACHINE_ID: &str = "machine_id";
+    /// Sends or accepts [`crate::ClientMessage::ClientInfo`].
+    pub const CLIENT_INFO: &str = "client_info";
+    /// Honors [`crate::CaptureOptions::stitch`].
+    pub const STITCH: &str = "stitch";
+
+    pub const ALL: [&str; 7] = [
+        CAPTURE_OPTIONS,
+        MONITOR_ERRORS,
+        SECRET_UPDATE,
+        SHUTDOWN,
+        MACHINE_ID,
+        CLIENT_INFO,
+        STITCH,
+    ];
+}
+
+/// First message of each side, sent by the server before the auth challenge
+/// and by the client before its auth response. Lists are plain strings so
+/// that values added by newer versions do not break older peers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub app_version: String,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(app_version: &str, capabilities: Capabilities) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            app_version: app_version.to_string(),
+            capabilities,
+        }
+    }
+
+    /// What a peer that sends no hello can do: protocol version 1, JPEG
+    /// only and none of the optional features. Its version is unknown.
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            app_version: String::new(),
+            capabilities: Capabilities {
+                formats: vec![ImageFormat::Jpeg.name().to_string()],
+                ..Capabilities::default()
+            },
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version >= MIN_PROTOCOL_VERSION
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Image formats, as in [`ImageFormat::name`].
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// Compression schemes for screenshot payloads besides none.
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// Entries of [`features`].
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Everything this build supports.
+    pub fn all() -> Self {
+        Self {
+            formats: ImageFormat::ALL
+                .iter()
+                .map(|f| f.name().to_string())
+                .collect(),
+            compression: Vec::new(),
+            features: features::ALL.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    pub fn supports_format(&self, format: ImageFormat) -> bool {
+        self.formats.iter().any(|f| f == format.name())
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
-    AuthChallenge { nonce: String },
-    ScreenshotRequest { request_id: String },
+    Hello(Hello),
+    AuthChallenge {
+        nonce: String,
+    },
+    AuthConfirm {
+        hmac: String,
+    },
+    AuthRejected {
+        reason: AuthRejectReason,
+    },
+    /// Answers an [`ClientMessage::Enroll`] with the key the client has to
+    /// authenticate with once an administrator approved it.
+    Enrolled {
+        key: String,
+    },
+    /// Pushes a rotated shared secret key to a client that authenticated
+    /// with the old one, so that it can store it in its settings. The key is
+    /// wrapped with [`wrap_secret`] under the old one.
+    SecretUpdate {
+        wrapped_key: String,
+        hmac: String,
+    },
+    ScreenshotRequest {
+        request_id: String,
+        #[serde(default)]
+        options: CaptureOptions,
+    },
+    /// Sent before the server closes the connection on shutdown, so that the
+    /// client reconnects after the given delay instead of backing off.
+    Shutdown {
+        reconnect_after_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthRejectReason {
+    InvalidCredentials,
+    PendingApproval,
+    Revoked,
+    InvalidEnrollmentToken,
+    AlreadyEnrolled,
+    /// The name is empty, too long or contains control characters.
+    InvalidName,
+    /// Another machine is connected under the same name.
+    NameInUse,
+    /// The client speaks a protocol version the server no longer supports.
+    UnsupportedVersion,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monitors: Option<Vec<u32>>,
+    #[serde(default)]
+    pub format: ImageFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub grayscale: bool,
+    /// Compose the selected monitors into one image of the virtual desktop
+    /// instead of sending one per monitor.
+    #[serde(default)]
+    pub stitch: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Webp];
+
+    /// The name used in requests and in [`Capabilities::formats`].
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.extension() == extension)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
+    Hello(Hello),
     AuthResponse {
         name: String,
         hmac: String,
+        /// Challenge for the server's proof; missing from clients that
+        /// predate mutual authentication, which are refused.
+        #[serde(default)]
+        client_nonce: Option<String>,
+        /// Random ID the client generated once and keeps across restarts,
+        /// telling a reconnecting machine apart from another one that uses
+        /// the same name.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        machine_id: Option<String>,
     },
+    /// Sent instead of an [`ClientMessage::AuthResponse`] by a client that
+    /// has no key yet but holds a one-time enrollment token.
+    Enroll {
+        name: String,
+        token: String,
+    },
+    /// Describes the machine, sent after authenticating to servers that
+    /// support [`features::CLIENT_INFO`] and again whenever the displays change.
+    ClientInfo(ClientInfo),
     ScreenshotResponse {
         request_id: String,
         screenshots: Vec<ScreenshotData>,
+        #[serde(default)]
+        errors: Vec<MonitorError>,
+    },
+    ScreenshotError {
+        request_id: String,
+        reason: CaptureErrorReason,
+        detail: String,
     },
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// As in `std::env::consts::OS`, e.g. `windows` or `macos`.
+    pub os: String,
+    #[serde(default)]
+    pub arch: String,
+    pub hostname: String,
+    pub app_version: String,
+    #[serde(default)]
+    pub monitors: Vec<MonitorInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MonitorInfo {
+    /// The number used in [`CaptureOptions::monitors`] and
+    /// [`ScreenshotData::monitor`].
+    pub index: u32,
+    /// The identifier the OS gives the display.
+    #[serde(default)]
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+    #[serde(default = "default_scale_factor")]
+    pub scale_factor: f32,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+fn default_scale_factor() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureErrorReason {
+    PermissionDenied,
+    EnumerationFailed,
+    CaptureFailed,
+    EncodingFailed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitorError {
+    pub monitor: u32,
+    pub reason: CaptureErrorReason,
+    pub detail: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScreenshotData {
     pub monitor: u32,
     pub data: Vec<u8>,
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// `None` from clients that predate it.
+    #[serde(default)]
+    pub meta: Option<ScreenshotMeta>,
+}
+
+/// What a client knows about a screenshot besides its bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScreenshotMeta {
+    /// Size of the encoded image, smaller than the display's when the
+    /// request asked to scale it down.
+    pub width: u32,
+    pub height: u32,
+    /// As in [`MonitorInfo::name`]; empty for stitched images.
+    #[serde(default)]
+    pub display_name: String,
+    /// Position of the display's top-left corner in the virtual desktop.
+    pub x: i32,
+    pub y: i32,
+    #[serde(default = "default_scale_factor")]
+    pub scale_factor: f32,
+    /// Taken from the client's clock.
+    pub captured_at: DateTime<Utc>,
+    /// Monitors composed into a stitched image; empty for single monitors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stitched: Vec<u32>,
 }
 
 pub fn compute_hmac(secret: &str, nonce: &str) -> String {
@@ -38,6 +351,193 @@ pub fn compute_hmac(secret: &str, nonce: &str) -> String {
 }
 
 pub fn verify_hmac(secret: &str, nonce: &str, hmac_hex: &str) -> bool {
-    let expected = compute_hmac(secret, nonce);
-    expected == hmac_hex
+    let Ok(expected) = hex::decode(hmac_hex) else {
+        return false;
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(nonce.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// The message the client signs to answer a challenge. Both proofs are
+/// prefixed so that a client proof for a crafted challenge can never pass
+/// as a server proof, and the other way round.
+fn client_proof_message(server_nonce: &str) -> String {
+    format!("client:{server_nonce}")
+}
+
+/// The message the server signs to prove knowledge of the secret, bound to
+/// both nonces.
+fn server_proof_message(server_nonce: &str, client_nonce: &str) -> String {
+    format!("server:{server_nonce}:{client_nonce}")
+}
+
+pub fn compute_client_hmac(secret: &str, server_nonce: &str) -> String {
+    compute_hmac(secret, &client_proof_message(server_nonce))
+}
+
+pub fn verify_client_hmac(secret: &str, server_nonce: &str, hmac_hex: &str) -> bool {
+    verify_hmac(secret, &client_proof_message(server_nonce), hmac_hex)
+}
+
+pub fn compute_server_hmac(secret: &str, server_nonce: &str, client_nonce: &str) -> String {
+    compute_hmac(secret, &server_proof_message(server_nonce, client_nonce))
+}
+
+pub fn verify_server_hmac(
+    secret: &str,
+    server_nonce: &str,
+    client_nonce: &str,
+    hmac_hex: &str,
+) -> bool {
+    verify_hmac(
+        secret,
+        &server_proof_message(server_nonce, client_nonce),
+        hmac_hex,
+    )
+}
+
+/// Encrypts `new_secret` for a [`ServerMessage::SecretUpdate`] by XORing it
+/// with an HMAC keystream keyed by the secret the connection authenticated
+/// with and bound to its nonces, so that it never crosses the wire in the
+/// clear. Returns the wrapped key and an HMAC over it, both hex encoded.
+pub fn wrap_secret(
+    old_secret: &str,
+    server_nonce: &str,
+    client_nonce: &str,
+    new_secret: &str,
+) -> (String, String) {
+    let wrapped = hex::encode(xor_keystream(
+        old_secret,
+        server_nonce,
+        client_nonce,
+        new_secret.as_bytes(),
+    ));
+    let hmac = compute_hmac(
+        old_secret,
+        &update_tag_message(server_nonce, client_nonce, &wrapped),
+    );
+    (wrapped, hmac)
+}
+
+/// Reverses [`wrap_secret`]; `None` if the update was not wrapped for this
+/// connection or was tampered with.
+pub fn unwrap_secret(
+    old_secret: &str,
+    server_nonce: &str,
+    client_nonce: &str,
+    wrapped_hex: &str,
+    hmac_hex: &str,
+) -> Option<String> {
+    if !verify_hmac(
+        old_secret,
+        &update_tag_message(server_nonce, client_nonce, wrapped_hex),
+        hmac_hex,
+    ) {
+        return None;
+    }
+    let wrapped = hex::decode(wrapped_hex).ok()?;
+    String::from_utf8(xor_keystream(
+        old_secret,
+        server_nonce,
+        client_nonce,
+        &wrapped,
+    ))
+    .ok()
+}
+
+fn update_tag_message(server_nonce: &str, client_nonce: &str, wrapped_hex: &str) -> String {
+    format!("update:{server_nonce}:{client_nonce}:{wrapped_hex}")
+}
+
+fn xor_keystream(secret: &str, server_nonce: &str, client_nonce: &str, data: &[u8]) -> Vec<u8> {
+    data.chunks(32)
+        .enumerate()
+        .flat_map(|(block, chunk)| {
+            let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+                .expect("HMAC can take key of any size");
+            mac.update(format!("update-key:{server_nonce}:{client_nonce}:{block}").as_bytes());
+            let key = mac.finalize().into_bytes();
+            chunk
+                .iter()
+                .zip(key)
+                .map(|(byte, key)| byte ^ key)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    #[test]
+    fn client_proof_verifies() {
+        let hmac = compute_client_hmac(SECRET, "n1");
+        assert!(verify_client_hmac(SECRET, "n1", &hmac));
+        assert!(!verify_client_hmac(SECRET, "n2", &hmac));
+        assert!(!verify_client_hmac("other", "n1", &hmac));
+        assert!(!verify_client_hmac(SECRET, "n1", "not hex"));
+    }
+
+    #[test]
+    fn server_proof_is_bound_to_both_nonces() {
+        let hmac = compute_server_hmac(SECRET, "s1", "c1");
+        assert!(verify_server_hmac(SECRET, "s1", "c1", &hmac));
+        assert!(!verify_server_hmac(SECRET, "s2", "c1", &hmac));
+        assert!(!verify_server_hmac(SECRET, "s1", "c2", &hmac));
+        assert!(!verify_server_hmac("other", "s1", "c1", &hmac));
+    }
+
+    #[test]
+    fn client_proof_never_verifies_as_server_proof() {
+        // A rogue server picks challenges that look like server proof messages.
+        for challenge in ["s1:c1", "server:s1:c1", ":s1:c1"] {
+            let hmac = compute_client_hmac(SECRET, challenge);
+            assert!(!verify_server_hmac(SECRET, "s1", "c1", &hmac));
+            assert!(!verify_server_hmac(SECRET, "server:s1", "c1", &hmac));
+        }
+    }
+
+    #[test]
+    fn server_proof_never_verifies_as_client_proof() {
+        let hmac = compute_server_hmac(SECRET, "s1", "c1");
+        assert!(!verify_client_hmac(SECRET, "s1", &hmac));
+        assert!(!verify_client_hmac(SECRET, "s1:c1", &hmac));
+    }
+
+    #[test]
+    fn wrapped_secret_unwraps_only_for_its_connection() {
+        let new_secret = "n".repeat(64);
+        let (wrapped, hmac) = wrap_secret(SECRET, "s1", "c1", &new_secret);
+        assert!(!wrapped.contains(&hex::encode(&new_secret)));
+        assert_eq!(
+            unwrap_secret(SECRET, "s1", "c1", &wrapped, &hmac).as_deref(),
+            Some(new_secret.as_str())
+        );
+        assert_eq!(unwrap_secret(SECRET, "s2", "c1", &wrapped, &hmac), None);
+        assert_eq!(unwrap_secret(SECRET, "s1", "c2", &wrapped, &hmac), None);
+        assert_eq!(unwrap_secret("other", "s1", "c1", &wrapped, &hmac), None);
+    }
+
+    #[test]
+    fn tampered_wrapped_secret_is_rejected() {
+        let (wrapped, hmac) = wrap_secret(SECRET, "s1", "c1", "new");
+        let flipped = format!(
+            "{}{}",
+            if wrapped.starts_with('0') { '1' } else { '0' },
+            &wrapped[1..]
+        );
+        assert_eq!(unwrap_secret(SECRET, "s1", "c1", &flipped, &hmac), None);
+    }
+
+    #[test]
+    fn plain_hmac_is_not_a_client_proof() {
+        let hmac = compute_hmac(SECRET, "n1");
+        assert!(verify_hmac(SECRET, "n1", &hmac));
+        assert!(!verify_client_hmac(SECRET, "n1", &hmac));
+    }
 }
@@ -0,0 +1,209 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::http::ApiError;
+use crate::state::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Trigger new screenshot requests.
+    Capture,
+    /// Read images, history, request progress, clients and events.
+    Read,
+    /// Issue enrollment tokens and approve or revoke client credentials.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Capture => "capture",
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "capture" => Ok(Scope::Capture),
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ApiToken {
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl FromStr for ApiToken {
+    type Err = String;
+
+    /// Parses `<token>[:<scope>,<scope>...]`; a token without scopes is
+    /// granted all of them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, scopes) = match s.split_once(':') {
+            Some((token, scopes)) => (
+                token,
+                scopes
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => (s, vec![Scope::Capture, Scope::Read, Scope::Admin]),
+        };
+        if token.is_empty() {
+            return Err("API token must not be empty".to_string());
+        }
+
+        Ok(ApiToken {
+            token: token.to_string(),
+            scopes,
+        })
+    }
+}
+
+impl TryFrom<String> for ApiToken {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn presented_token(req: &Request) -> Option<String> {
+    if let Some(header) = req.headers().get(AUTHORIZATION)
+        && let Ok(value) = header.to_str()
+        && let Some(token) = value.strip_prefix("Bearer ")
+    {
+        return Some(token.trim().to_string());
+    }
+
+    form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.into_owned())
+}
+
+fn authorize(state: &AppState, req: &Request, scope: Scope) -> Result<(), ApiError> {
+    // Without configured tokens the API stays open, as before.
+    if state.config.api_tokens.is_empty() {
+        return Ok(());
+    }
+
+    let presented = presented_token(req).ok_or(ApiError::Unauthorized)?;
+    let token = state
+        .config
+        .api_tokens
+        .iter()
+        .find(|t| bool::from(t.token.as_bytes().ct_eq(presented.as_bytes())))
+        .ok_or(ApiError::Unauthorized)?;
+
+    if token.scopes.contains(&scope) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(format!(
+            "API token lacks the {} scope",
+            scope.as_str()
+        )))
+    }
+}
+
+async fn require(state: &AppState, scope: Scope, req: Request, next: Next) -> Response {
+    match authorize(state, &req, scope) {
+        Ok(()) => next.run(req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn require_capture(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    require(&state, Scope::Capture, req, next).await
+}
+
+pub async fn require_read(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    require(&state, Scope::Read, req, next).await
+}
+
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    require(&state, Scope::Admin, req, next).await
+}
+
+#[derive(Deserialize)]
+struct SignedUrlQuery {
+    expires: i64,
+    sig: String,
+}
+
+/// Only serves images through URLs signed by [`crate::signing::UrlSigner`].
+pub async fn require_signed_url(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let filename = req.uri().path().rsplit('/').next().unwrap_or_default();
+    let filename = percent_decode_str(filename).decode_utf8_lossy();
+    let valid = serde_html_form::from_str::<SignedUrlQuery>(req.uri().query().unwrap_or_default())
+        .is_ok_and(|q| state.url_signer.verify(&filename, q.expires, &q.sig));
+
+    if valid {
+        next.run(req).await
+    } else {
+        ApiError::Forbidden("image link is unsigned, invalid or expired".to_string())
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_without_scopes_gets_all_of_them() {
+        let token: ApiToken = "secret".parse().unwrap();
+        assert_eq!(token.token, "secret");
+        assert_eq!(token.scopes, [Scope::Capture, Scope::Read, Scope::Admin]);
+    }
+
+    #[test]
+    fn token_with_scopes_gets_only_those() {
+        let token: ApiToken = "secret:read,capture".parse().unwrap();
+        assert_eq!(token.token, "secret");
+        assert_eq!(token.scopes, [Scope::Read, Scope::Capture]);
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        assert!("".parse::<ApiToken>().is_err());
+        assert!(":read".parse::<ApiToken>().is_err());
+        assert!("secret:".parse::<ApiToken>().is_err());
+        assert!("secret:read,write".parse::<ApiToken>().is_err());
+        assert!("secret:Read".parse::<ApiToken>().is_err());
+    }
+}
@@ -8,21 +8,22 @@ pub async fn cleanup_loop(state: Arc<AppState>) {
     loop {
         tokio::time::sleep(interval).await;
 
-        let retention = chrono::Duration::minutes(state.retention_mins as i64);
+        let retention = chrono::Duration::minutes(state.config.retention_mins as i64);
         let cutoff = Utc::now() - retention;
 
-        let mut images = state.stored_images.lock().await;
-        let mut i = 0;
-        while i < images.len() {
-            if images[i].created_at < cutoff {
-                let img = images.swap_remove(i);
-                if let Err(e) = std::fs::remove_file(&img.path) {
-                    tracing::warn!("failed to remove expired image {:?}: {}", img.path, e);
-                } else {
-                    tracing::info!("removed expired image: {:?}", img.path);
-                }
+        state.disconnect_unapproved().await;
+        state.push_rotated_secret().await;
+
+        state
+            .prune_finished_requests(retention.to_std().unwrap_or_default())
+            .await;
+
+        for img in state.images.take_expired(cutoff).await {
+            let path = state.images.path_of(&img);
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("failed to remove expired image {:?}: {}", path, e);
             } else {
-                i += 1;
+                tracing::info!("removed expired image: {:?}", path);
             }
         }
     }
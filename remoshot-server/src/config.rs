@@ -0,0 +1,513 @@
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth::ApiToken;
+
+/// Settings that can be given as flags, as `REMOSHOT_*` environment
+/// variables or in the TOML config file, in that order of precedence.
+#[derive(Debug, Default, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    /// WebSocket port for client connections
+    #[arg(long, env = "REMOSHOT_WS_PORT")]
+    pub ws_port: Option<u16>,
+
+    /// HTTP listen address, e.g. 127.0.0.1:8113
+    #[arg(long, env = "REMOSHOT_HTTP_ADDR")]
+    pub http_addr: Option<String>,
+
+    /// Screenshot retention time in minutes
+    #[arg(long, env = "REMOSHOT_RETENTION")]
+    pub retention: Option<u64>,
+
+    /// Default time in seconds to wait for clients to answer a screenshot request [default: 10]
+    #[arg(long, env = "REMOSHOT_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// Upper bound in seconds for the per-request `timeout_ms` override [default: 60]
+    #[arg(long, env = "REMOSHOT_MAX_REQUEST_TIMEOUT")]
+    pub max_request_timeout: Option<u64>,
+
+    /// Time in seconds running requests get to finish when the server shuts down [default: 10]
+    #[arg(long, env = "REMOSHOT_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// HTTP API token as `<token>[:<scope>,...]` with scopes `capture`, `read` and `admin`;
+    /// may be repeated. Without any token the HTTP API is unauthenticated
+    #[arg(
+        long = "api-token",
+        env = "REMOSHOT_API_TOKENS",
+        value_delimiter = ' ',
+        hide_env_values = true
+    )]
+    pub api_tokens: Option<Vec<ApiToken>>,
+
+    /// Validity in minutes of signed image URLs, defaults to the retention time
+    #[arg(long, env = "REMOSHOT_IMAGE_URL_TTL")]
+    pub image_url_ttl: Option<u64>,
+
+    /// Only accept clients with an approved per-client key, not the shared secret key
+    #[arg(long, env = "REMOSHOT_REQUIRE_ENROLLMENT", num_args = 0..=1, default_missing_value = "true")]
+    pub require_enrollment: Option<bool>,
+
+    /// Also accept clients that send no hello (1.2.1 and older), which cannot verify the server
+    #[arg(long, env = "REMOSHOT_ALLOW_LEGACY_CLIENTS", num_args = 0..=1, default_missing_value = "true")]
+    pub allow_legacy_clients: Option<bool>,
+
+    /// What to do when a client connects under the name of another connected machine [default: suffix]
+    #[arg(long, env = "REMOSHOT_NAME_COLLISION")]
+    pub name_collision: Option<NameCollision>,
+
+    /// PEM certificate chain to serve HTTPS and WSS with
+    #[arg(long, env = "REMOSHOT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[arg(long, env = "REMOSHOT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS and WSS with a self-signed certificate, generated on first start
+    #[arg(long, env = "REMOSHOT_TLS_SELF_SIGNED", num_args = 0..=1, default_missing_value = "true")]
+    pub tls_self_signed: Option<bool>,
+
+    /// Extra host name or IP address the self-signed certificate is issued for,
+    /// besides localhost and this machine's host name; may be repeated
+    #[arg(
+        long = "tls-self-signed-name",
+        env = "REMOSHOT_TLS_SELF_SIGNED_NAMES",
+        value_delimiter = ' '
+    )]
+    pub tls_self_signed_names: Option<Vec<String>>,
+
+    /// Directory screenshots are stored in [default: images]
+    #[arg(long, env = "REMOSHOT_IMAGE_DIR")]
+    pub image_dir: Option<PathBuf>,
+
+    /// Journal of stored screenshots [default: images.jsonl]
+    #[arg(long, env = "REMOSHOT_INDEX_FILE")]
+    pub index_file: Option<PathBuf>,
+
+    /// File holding the shared secret key [default: secret.key]
+    #[arg(long, env = "REMOSHOT_SECRET_KEY_FILE")]
+    pub secret_key_file: Option<PathBuf>,
+
+    /// File holding per-client keys and enrollment tokens [default: clients.json]
+    #[arg(long, env = "REMOSHOT_CLIENTS_FILE")]
+    pub clients_file: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn load_file(path: &Path) -> Result<Self, ConfigError> {
+        let file_error = |message: String| ConfigError::File {
+            path: path.to_path_buf(),
+            message,
+        };
+        let data = std::fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+        toml::from_str(&data).map_err(|e| file_error(e.to_string()))
+    }
+
+    /// Fills every setting missing here from `fallback`.
+    pub fn or(self, fallback: Options) -> Options {
+        Options {
+            ws_port: self.ws_port.or(fallback.ws_port),
+            http_addr: self.http_addr.or(fallback.http_addr),
+            retention: self.retention.or(fallback.retention),
+            request_timeout: self.request_timeout.or(fallback.request_timeout),
+            max_request_timeout: self.max_request_timeout.or(fallback.max_request_timeout),
+            shutdown_timeout: self.shutdown_timeout.or(fallback.shutdown_timeout),
+            api_tokens: self.api_tokens.or(fallback.api_tokens),
+            image_url_ttl: self.image_url_ttl.or(fallback.image_url_ttl),
+            require_enrollment: self.require_enrollment.or(fallback.require_enrollment),
+            allow_legacy_clients: self.allow_legacy_clients.or(fallback.allow_legacy_clients),
+            tls_cert: self.tls_cert.or(fallback.tls_cert),
+            tls_key: self.tls_key.or(fallback.tls_key),
+            tls_self_signed: self.tls_self_signed.or(fallback.tls_self_signed),
+            tls_self_signed_names: self
+                .tls_self_signed_names
+                .or(fallback.tls_self_signed_names),
+            image_dir: self.image_dir.or(fallback.image_dir),
+            index_file: self.index_file.or(fallback.index_file),
+            secret_key_file: self.secret_key_file.or(fallback.secret_key_file),
+            clients_file: self.clients_file.or(fallback.clients_file),
+            name_collision: self.name_collision.or(fallback.name_collision),
+        }
+    }
+
+    pub fn secret_key_file(&self) -> PathBuf {
+        self.secret_key_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("secret.key"))
+    }
+
+    pub fn clients_file(&self) -> PathBuf {
+        self.clients_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("clients.json"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NameCollision {
+    /// Refuse the new connection.
+    Reject,
+    /// Register the new connection as `<name>-2`, `<name>-3`, ...
+    Suffix,
+    /// Drop the old connection.
+    Replace,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str),
+    Invalid {
+        field: &'static str,
+        message: String,
+    },
+    File {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(field) => write!(
+                f,
+                "missing `{field}`: pass --{}, set REMOSHOT_{} or add it to the config file",
+                field.replace('_', "-"),
+                field.to_uppercase()
+            ),
+            ConfigError::Invalid { field, message } => write!(f, "invalid `{field}`: {message}"),
+            ConfigError::File { path, message } => {
+                write!(f, "cannot load config file {}: {message}", path.display())
+            }
+        }
+    }
+}
+
+fn invalid(field: &'static str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        message: message.into(),
+    }
+}
+
+pub enum TlsSource {
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    /// Generated on first start for localhost, this machine's host name and
+    /// `names`.
+    SelfSigned {
+        names: Vec<String>,
+    },
+}
+
+pub struct ServerConfig {
+    pub ws_port: u16,
+    pub http_addr: SocketAddr,
+    pub image_dir: PathBuf,
+    pub index_file: PathBuf,
+    pub secret_key_file: PathBuf,
+    pub retention_mins: u64,
+    pub request_timeout: Duration,
+    pub max_request_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub image_url_ttl: Duration,
+    pub api_tokens: Vec<ApiToken>,
+    /// Refuse clients that authenticate with the shared secret key.
+    pub require_enrollment: bool,
+    /// Accept clients that predate the hello exchange, authenticating them
+    /// the way they expect without proving the server to them.
+    pub allow_legacy_clients: bool,
+    pub tls: Option<TlsSource>,
+    pub name_collision: NameCollision,
+}
+
+impl ServerConfig {
+    /// Where generated server files go: next to the secret key.
+    pub fn state_dir(&self) -> &Path {
+        match self.secret_key_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Validates the merged options. Required settings that are missing are
+    /// asked for on the terminal when `interactive`, otherwise reported.
+    pub fn resolve(options: Options, interactive: bool) -> Result<Self, ConfigError> {
+        let secret_key_file = options.secret_key_file();
+        let required = |field: &'static str, value: Option<String>, question: &str| match value {
+            Some(value) => Ok(value),
+            None if interactive => Ok(prompt(question)),
+            None => Err(ConfigError::Missing(field)),
+        };
+
+        let ws_port: u16 = required(
+            "ws_port",
+            options.ws_port.map(|p| p.to_string()),
+            "WebSocket port for client connections",
+        )?
+        .parse()
+        .map_err(|e| invalid("ws_port", format!("{e}")))?;
+        if ws_port == 0 {
+            return Err(invalid("ws_port", "must not be 0"));
+        }
+
+        let http_addr = required(
+            "http_addr",
+            options.http_addr,
+            "HTTP listen address (e.g. 127.0.0.1:8113)",
+        )?;
+        let http_addr: SocketAddr = http_addr
+            .parse()
+            .map_err(|e| invalid("http_addr", format!("{http_addr:?}: {e}")))?;
+
+        let retention_mins: u64 = required(
+            "retention",
+            options.retention.map(|r| r.to_string()),
+            "Screenshot retention time in minutes",
+        )?
+        .parse()
+        .map_err(|e| invalid("retention", format!("{e}")))?;
+        if retention_mins == 0 {
+            return Err(invalid("retention", "must be at least one minute"));
+        }
+
+        let request_timeout = Duration::from_secs(options.request_timeout.unwrap_or(10));
+        if request_timeout.is_zero() {
+            return Err(invalid("request_timeout", "must be at least one second"));
+        }
+        let max_request_timeout =
+            Duration::from_secs(options.max_request_timeout.unwrap_or(60)).max(request_timeout);
+
+        let image_url_ttl = options.image_url_ttl.unwrap_or(retention_mins);
+        if image_url_ttl == 0 {
+            return Err(invalid("image_url_ttl", "must be at least one minute"));
+        }
+
+        if options.tls_self_signed_names.is_some() && options.tls_self_signed != Some(true) {
+            return Err(invalid(
+                "tls_self_signed_names",
+                "is only used with tls_self_signed",
+            ));
+        }
+        let tls = match (
+            options.tls_cert,
+            options.tls_key,
+            options.tls_self_signed.unwrap_or(false),
+        ) {
+            (Some(_), Some(_), true) => {
+                return Err(invalid(
+                    "tls_self_signed",
+                    "cannot be combined with tls_cert and tls_key",
+                ));
+            }
+            (Some(cert), Some(key), false) => Some(TlsSource::Files { cert, key }),
+            (Some(_), None, _) => return Err(invalid("tls_key", "is required with tls_cert")),
+            (None, Some(_), _) => return Err(invalid("tls_cert", "is required with tls_key")),
+            (None, None, true) => Some(TlsSource::SelfSigned {
+                names: options.tls_self_signed_names.unwrap_or_default(),
+            }),
+            (None, None, false) => None,
+        };
+
+        Ok(ServerConfig {
+            ws_port,
+            http_addr,
+            image_dir: options.image_dir.unwrap_or_else(|| PathBuf::from("images")),
+            index_file: options
+                .index_file
+                .unwrap_or_else(|| PathBuf::from("images.jsonl")),
+            secret_key_file,
+            retention_mins,
+            request_timeout,
+            max_request_timeout,
+            shutdown_timeout: Duration::from_secs(options.shutdown_timeout.unwrap_or(10)),
+            image_url_ttl: Duration::from_secs(image_url_ttl * 60),
+            api_tokens: options.api_tokens.unwrap_or_default(),
+            require_enrollment: options.require_enrollment.unwrap_or(false),
+            allow_legacy_clients: options.allow_legacy_clients.unwrap_or(false),
+            tls,
+            name_collision: options.name_collision.unwrap_or(NameCollision::Suffix),
+        })
+    }
+}
+
+fn prompt(msg: &str) -> String {
+    dialoguer::Input::<String>::new()
+        .with_prompt(msg)
+        .interact_text()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required() -> Options {
+        Options {
+            ws_port: Some(8283),
+            http_addr: Some("127.0.0.1:8113".to_string()),
+            retention: Some(30),
+            ..Default::default()
+        }
+    }
+
+    fn error_field(options: Options) -> &'static str {
+        match ServerConfig::resolve(options, false) {
+            Err(ConfigError::Missing(field) | ConfigError::Invalid { field, .. }) => field,
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("options were accepted"),
+        }
+    }
+
+    #[test]
+    fn defaults_fill_optional_settings() {
+        let config = ServerConfig::resolve(required(), false).unwrap();
+        assert_eq!(config.ws_port, 8283);
+        assert_eq!(config.http_addr, "127.0.0.1:8113".parse().unwrap());
+        assert_eq!(config.request_timeout, Duration::from_secs(10));
+        assert_eq!(config.max_request_timeout, Duration::from_secs(60));
+        assert_eq!(config.image_url_ttl, Duration::from_secs(30 * 60));
+        assert_eq!(config.secret_key_file, PathBuf::from("secret.key"));
+        assert_eq!(config.state_dir(), Path::new("."));
+        assert_eq!(config.name_collision, NameCollision::Suffix);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn missing_and_invalid_settings_are_reported() {
+        assert_eq!(
+            error_field(Options {
+                ws_port: None,
+                ..required()
+            }),
+            "ws_port"
+        );
+        assert_eq!(
+            error_field(Options {
+                ws_port: Some(0),
+                ..required()
+            }),
+            "ws_port"
+        );
+        assert_eq!(
+            error_field(Options {
+                http_addr: Some("nope".to_string()),
+                ..required()
+            }),
+            "http_addr"
+        );
+        assert_eq!(
+            error_field(Options {
+                retention: Some(0),
+                ..required()
+            }),
+            "retention"
+        );
+        assert_eq!(
+            error_field(Options {
+                request_timeout: Some(0),
+                ..required()
+            }),
+            "request_timeout"
+        );
+    }
+
+    #[test]
+    fn max_request_timeout_is_at_least_the_default() {
+        let config = ServerConfig::resolve(
+            Options {
+                request_timeout: Some(90),
+                ..required()
+            },
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.max_request_timeout, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn tls_settings_must_fit_together() {
+        let cert = Some(PathBuf::from("cert.pem"));
+        let key = Some(PathBuf::from("key.pem"));
+        let config = ServerConfig::resolve(
+            Options {
+                tls_cert: cert.clone(),
+                tls_key: key.clone(),
+                ..required()
+            },
+            false,
+        )
+        .unwrap();
+        assert!(matches!(config.tls, Some(TlsSource::Files { .. })));
+
+        assert_eq!(
+            error_field(Options {
+                tls_cert: cert.clone(),
+                ..required()
+            }),
+            "tls_key"
+        );
+        assert_eq!(
+            error_field(Options {
+                tls_key: key.clone(),
+                ..required()
+            }),
+            "tls_cert"
+        );
+        assert_eq!(
+            error_field(Options {
+                tls_cert: cert,
+                tls_key: key,
+                tls_self_signed: Some(true),
+                ..required()
+            }),
+            "tls_self_signed"
+        );
+        assert_eq!(
+            error_field(Options {
+                tls_self_signed_names: Some(vec!["example.com".to_string()]),
+                ..required()
+            }),
+            "tls_self_signed_names"
+        );
+    }
+
+    #[test]
+    fn earlier_sources_take_precedence() {
+        let flags = Options {
+            ws_port: Some(1),
+            ..Default::default()
+        };
+        let merged = flags.or(required());
+        assert_eq!(merged.ws_port, Some(1));
+        assert_eq!(merged.retention, Some(30));
+    }
+
+    #[test]
+    fn config_file_keys_match_the_flags() {
+        let options: Options = toml::from_str(
+            r#"
+            ws_port = 8283
+            api_tokens = ["viewer:read"]
+            secret_key_file = "/var/lib/remoshot/secret.key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.ws_port, Some(8283));
+        assert_eq!(options.api_tokens.as_ref().unwrap()[0].token, "viewer");
+
+        let config = ServerConfig::resolve(options.or(required()), false).unwrap();
+        assert_eq!(config.state_dir(), Path::new("/var/lib/remoshot"));
+
+        assert!(toml::from_str::<Options>("ws-port = 8283").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+use remoshot_common::AuthRejectReason;
+
+use crate::secret::generate_secret;
+
+/// Validity of enrollment tokens unless the administrator asks otherwise.
+pub const DEFAULT_TOKEN_TTL_MINS: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialStatus {
+    Pending,
+    Approved,
+    Revoked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientCredential {
+    pub key: String,
+    pub status: CredentialStatus,
+    pub enrolled_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approved_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentToken {
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What the API and the CLI show about a client credential; the key itself
+/// never leaves the server after enrollment.
+#[derive(Debug, Clone, Serialize)]
+pub struct CredentialSummary {
+    pub name: String,
+    pub status: CredentialStatus,
+    pub enrolled_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approved_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CredentialFile {
+    #[serde(default)]
+    clients: BTreeMap<String, ClientCredential>,
+    #[serde(default)]
+    enrollment_tokens: Vec<EnrollmentToken>,
+}
+
+/// Per-client keys and outstanding enrollment tokens, persisted as a JSON
+/// file. The file is re-read for every operation so that the CLI
+/// subcommands and a running server can both change it.
+pub struct CredentialStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl CredentialStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn create_enrollment_token(&self, ttl: chrono::Duration) -> io::Result<EnrollmentToken> {
+        self.update(|file| {
+            let now = Utc::now();
+            let token = EnrollmentToken {
+                token: generate_secret(),
+                created_at: now,
+                expires_at: now + ttl,
+            };
+            file.enrollment_tokens.push(token.clone());
+            token
+        })
+    }
+
+    /// Consumes `token` and registers `name` as pending with a fresh key.
+    /// A name that is already approved cannot be enrolled again until it is
+    /// revoked.
+    pub fn enroll(&self, name: &str, token: &str) -> io::Result<Result<String, AuthRejectReason>> {
+        self.update(|file| {
+            let now = Utc::now();
+            file.enrollment_tokens.retain(|t| t.expires_at > now);
+
+            let Some(pos) = file
+                .enrollment_tokens
+                .iter()
+                .position(|t| bool::from(t.token.as_bytes().ct_eq(token.as_bytes())))
+            else {
+                return Err(AuthRejectReason::InvalidEnrollmentToken);
+            };
+            if file
+                .clients
+                .get(name)
+                .is_some_and(|c| c.status == CredentialStatus::Approved)
+            {
+                return Err(AuthRejectReason::AlreadyEnrolled);
+            }
+
+            file.enrollment_tokens.remove(pos);
+            let key = generate_secret();
+            file.clients.insert(
+                name.to_string(),
+                ClientCredential {
+                    key: key.clone(),
+                    status: CredentialStatus::Pending,
+                    enrolled_at: now,
+                    approved_at: None,
+                    revoked_at: None,
+                },
+            );
+            Ok(key)
+        })
+    }
+
+    pub fn get(&self, name: &str) -> io::Result<Option<ClientCredential>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(load(&self.path)?.clients.remove(name))
+    }
+
+    pub fn list(&self) -> io::Result<Vec<CredentialSummary>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(load(&self.path)?
+            .clients
+            .into_iter()
+            .map(|(name, c)| summarize(name, &c))
+            .collect())
+    }
+
+    /// Moves the credential of `name` to `status`; returns `None` when the
+    /// client never enrolled.
+    pub fn set_status(
+        &self,
+        name: &str,
+        status: CredentialStatus,
+    ) -> io::Result<Option<CredentialSummary>> {
+        self.update(|file| {
+            let cred = file.clients.get_mut(name)?;
+            cred.status = status;
+            match status {
+                CredentialStatus::Approved => {
+                    cred.approved_at = Some(Utc::now());
+                    cred.revoked_at = None;
+                }
+                CredentialStatus::Revoked => cred.revoked_at = Some(Utc::now()),
+                CredentialStatus::Pending => {}
+            }
+            Some(summarize(name.to_string(), cred))
+        })
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut CredentialFile) -> T) -> io::Result<T> {
+        let _guard = self.lock.lock().unwrap();
+        let mut file = load(&self.path)?;
+        let result = f(&mut file);
+        save(&self.path, &file)?;
+        Ok(result)
+    }
+}
+
+fn summarize(name: String, cred: &ClientCredential) -> CredentialSummary {
+    CredentialSummary {
+        name,
+        status: cred.status,
+        enrolled_at: cred.enrolled_at,
+        approved_at: cred.approved_at,
+        revoked_at: cred.revoked_at,
+    }
+}
+
+fn load(path: &Path) -> io::Result<CredentialFile> {
+    match fs::read_to_string(path) {
+        Ok(data) => serde_json::from_str(&data).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(CredentialFile::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(path: &Path, file: &CredentialFile) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut tmp = crate::secret::create_private(&tmp_path)?;
+        tmp.write_all(serde_json::to_string_pretty(file).unwrap().as_bytes())?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use remoshot_common::ClientInfo;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::state::ClientStatus;
+
+const HISTORY_LEN: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    ClientConnected {
+        client_id: usize,
+        name: String,
+    },
+    ClientDisconnected {
+        client_id: usize,
+        name: String,
+    },
+    /// A client reported its system details and displays, after connecting
+    /// or because they changed.
+    ClientInfo {
+        client_id: usize,
+        name: String,
+        info: ClientInfo,
+    },
+    /// A client enrolled and waits for an administrator's approval.
+    ClientEnrolled {
+        name: String,
+    },
+    RequestStarted {
+        request_id: String,
+        clients: Vec<String>,
+    },
+    ImageStored {
+        request_id: String,
+        client: String,
+        monitor: u32,
+        stitched: bool,
+        url: String,
+    },
+    RequestCompleted {
+        request_id: String,
+        elapsed_ms: u64,
+        clients: HashMap<String, ClientStatus>,
+    },
+}
+
+impl ServerEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerEvent::ClientConnected { .. } => "client_connected",
+            ServerEvent::ClientDisconnected { .. } => "client_disconnected",
+            ServerEvent::ClientInfo { .. } => "client_info",
+            ServerEvent::ClientEnrolled { .. } => "client_enrolled",
+            ServerEvent::RequestStarted { .. } => "request_started",
+            ServerEvent::ImageStored { .. } => "image_stored",
+            ServerEvent::RequestCompleted { .. } => "request_completed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+/// Fan-out of server activity to `/events` subscribers. The most recent
+/// events are kept so that reconnecting consumers can resume by id.
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<Event>>,
+    inner: Mutex<EventBusInner>,
+}
+
+struct EventBusInner {
+    next_id: u64,
+    history: VecDeque<Arc<Event>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(HISTORY_LEN);
+        Self {
+            tx,
+            inner: Mutex::new(EventBusInner {
+                next_id: 1,
+                history: VecDeque::with_capacity(HISTORY_LEN),
+            }),
+        }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        let event = Arc::new(Event {
+            id: inner.next_id,
+            at: Utc::now(),
+            event,
+        });
+        inner.next_id += 1;
+
+        if inner.history.len() >= HISTORY_LEN {
+            inner.history.pop_front();
+        }
+        inner.history.push_back(event.clone());
+        let _ = self.tx.send(event);
+    }
+
+    /// Returns the buffered events after `last_id` together with a receiver
+    /// for everything published afterwards.
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+        let inner = self.inner.lock().unwrap();
+        let replay = match last_id {
+            Some(last_id) => inner
+                .history
+                .iter()
+                .filter(|e| e.id > last_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (replay, self.tx.subscribe())
+    }
+}
@@ -1,36 +1,224 @@
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::Router;
-use axum::extract::{ConnectInfo, State};
-use axum::response::Json;
-use axum::routing::get;
-use tokio::sync::{Mutex, oneshot};
+use axum::extract::{ConnectInfo, Path, RawQuery, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
+use axum::{Router, middleware};
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, broadcast};
 use tower_http::services::ServeDir;
+use wildmatch::WildMatch;
 
-use crate::state::{AppState, PendingRequest};
+use remoshot_common::{Capabilities, CaptureOptions, ClientInfo, ImageFormat, ScreenshotMeta};
 
-pub async fn run_http_server(addr: SocketAddr, state: Arc<AppState>, image_dir: PathBuf) {
-    let app = Router::new()
+use crate::credentials::{
+    CredentialStatus, CredentialSummary, DEFAULT_TOKEN_TTL_MINS, EnrollmentToken,
+};
+use crate::events::ServerEvent;
+use crate::index::StoredImage;
+use crate::secret::DEFAULT_ROTATION_GRACE_MINS;
+use crate::state::{AppState, ClientResult, PendingRequest};
+use crate::{auth, tls};
+
+pub async fn run_http_server(
+    addr: SocketAddr,
+    state: Arc<AppState>,
+    image_dir: PathBuf,
+    tls: Option<RustlsConfig>,
+) {
+    let capture = Router::new()
         .route("/screenshot", get(screenshot_handler))
+        .route("/requests", post(create_request_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_capture,
+        ));
+
+    let read = Router::new()
+        .route("/requests/{id}", get(request_status_handler))
+        .route("/clients", get(clients_handler))
+        .route("/history", get(history_handler))
+        .route("/events", get(events_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_read,
+        ));
+
+    let admin = Router::new()
+        .route("/enrollment-tokens", post(create_enrollment_token_handler))
+        .route("/credentials", get(credentials_handler))
+        .route(
+            "/credentials/{name}/approve",
+            post(approve_credential_handler),
+        )
+        .route(
+            "/credentials/{name}/revoke",
+            post(revoke_credential_handler),
+        )
+        .route("/secret/rotate", post(rotate_secret_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    let images = Router::new()
         .nest_service("/images", ServeDir::new(image_dir))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_signed_url,
+        ));
+
+    let shutdown = state.shutdown.clone();
+    let app = capture
+        .merge(read)
+        .merge(admin)
+        .merge(images)
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    tls::serve(addr, app, tls, shutdown).await;
 }
 
-async fn screenshot_handler(
-    State(state): State<Arc<AppState>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> Json<HashMap<String, Vec<String>>> {
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(String),
+    UnknownClients(Vec<String>),
+    Internal(String),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(msg) => (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": msg })),
+            )
+                .into_response(),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(serde_json::json!({ "error": "missing or invalid API token" })),
+            )
+                .into_response(),
+            ApiError::Forbidden(msg) => (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "error": msg })),
+            )
+                .into_response(),
+            ApiError::NotFound(msg) => (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "error": msg })),
+            )
+                .into_response(),
+            ApiError::UnknownClients(unknown) => (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "error": "unknown clients", "unknown": unknown })),
+            )
+                .into_response(),
+            ApiError::Internal(msg) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": msg })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+fn parse_query<T: serde::de::DeserializeOwned>(query: Option<String>) -> Result<T, ApiError> {
+    serde_html_form::from_str(query.as_deref().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("invalid query: {e}")))
+}
+
+#[derive(Deserialize)]
+struct ScreenshotQuery {
+    #[serde(default)]
+    client: Vec<String>,
+    format: Option<String>,
+    #[serde(default)]
+    monitor: Vec<u32>,
+    image_format: Option<ImageFormat>,
+    quality: Option<u8>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    #[serde(default)]
+    grayscale: bool,
+    #[serde(default)]
+    stitch: bool,
+    timeout_ms: Option<u64>,
+}
+
+impl ScreenshotQuery {
+    fn timeout(&self, state: &AppState) -> Result<Duration, ApiError> {
+        match self.timeout_ms {
+            None => Ok(state.config.request_timeout),
+            Some(0) => Err(ApiError::BadRequest(
+                "timeout_ms must be positive".to_string(),
+            )),
+            Some(ms) => Ok(Duration::from_millis(ms).min(state.config.max_request_timeout)),
+        }
+    }
+
+    fn capture_options(&self) -> Result<CaptureOptions, ApiError> {
+        if let Some(quality) = self.quality
+            && !(1..=100).contains(&quality)
+        {
+            return Err(ApiError::BadRequest(
+                "quality must be between 1 and 100".to_string(),
+            ));
+        }
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            return Err(ApiError::BadRequest(
+                "max_width and max_height must be positive".to_string(),
+            ));
+        }
+
+        Ok(CaptureOptions {
+            monitors: (!self.monitor.is_empty()).then(|| self.monitor.clone()),
+            format: self.image_format.unwrap_or_default(),
+            quality: self.quality,
+            max_width: self.max_width,
+            max_height: self.max_height,
+            grayscale: self.grayscale,
+            stitch: self.stitch,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ScreenshotResponseV2 {
+    request_id: String,
+    elapsed_ms: u64,
+    clients: HashMap<String, ClientResult>,
+}
+
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Sends a screenshot request to the selected clients and finishes it once
+/// every client has answered or the timeout elapses.
+async fn dispatch(
+    state: &Arc<AppState>,
+    addr: SocketAddr,
+    query: &ScreenshotQuery,
+) -> Result<(String, Arc<Mutex<PendingRequest>>, Duration), ApiError> {
+    let options = query.capture_options()?;
+    let timeout = query.timeout(state)?;
+
+    let targets = state
+        .select_clients(&query.client)
+        .await
+        .map_err(ApiError::UnknownClients)?;
+
     let request_id = uuid::Uuid::new_v4().to_string();
     tracing::info!(
         "screenshot request from {}, request_id: {}",
@@ -38,57 +226,443 @@ async fn screenshot_handler(
         request_id
     );
 
-    let (tx, rx) = oneshot::channel();
-
-    let expected = state.broadcast_screenshot_request(&request_id).await;
+    let pending = state.start_request(&request_id, &targets, &options).await;
+    let (targeted, mut done) = {
+        let req = pending.lock().await;
+        (req.targets.len(), req.subscribe())
+    };
     tracing::info!(
-        "broadcasted screenshot request {} to {} clients",
+        "sent screenshot request {} to {} clients",
         request_id,
-        expected
+        targeted
     );
 
-    if expected == 0 {
+    if targeted == 0 {
         tracing::warn!("no clients available for screenshot request {}", request_id);
-        return Json(HashMap::new());
     }
 
-    let pending = Arc::new(Mutex::new(PendingRequest {
-        expected,
-        received: HashMap::new(),
-        notify: Some(tx),
-    }));
+    let timeout_state = state.clone();
+    let timeout_pending = pending.clone();
+    let timeout_id = request_id.clone();
+    tokio::spawn(async move {
+        let completed = tokio::time::timeout(timeout, done.wait_for(|d| *d))
+            .await
+            .is_ok();
+        if completed {
+            tracing::info!("received all expected responses for request {}", timeout_id);
+        } else {
+            tracing::warn!("timeout or partial responses for request {}", timeout_id);
+        }
+
+        let mut req = timeout_pending.lock().await;
+        req.finish();
+        timeout_state.events.publish(ServerEvent::RequestCompleted {
+            request_id: timeout_id,
+            elapsed_ms: req.elapsed_ms(),
+            clients: req
+                .results()
+                .into_iter()
+                .map(|(name, result)| (name, result.status))
+                .collect(),
+        });
+    });
 
-    {
-        let mut requests = state.pending_requests.write().await;
-        requests.insert(request_id.clone(), pending.clone());
-    }
+    Ok((request_id, pending, timeout))
+}
 
-    let result = match tokio::time::timeout(std::time::Duration::from_secs(10), rx).await {
-        Ok(Ok(map)) => {
-            tracing::info!("received all expected responses for request {}", request_id);
-            map
-        }
-        _ => {
-            tracing::warn!("timeout or partial responses for request {}", request_id);
-            let req = pending.lock().await;
-            req.received.clone()
+async fn wait_finished(pending: &Mutex<PendingRequest>, max: Duration) {
+    let mut done = pending.lock().await.subscribe();
+    let _ = tokio::time::timeout(max, done.wait_for(|d| *d)).await;
+}
+
+async fn screenshot_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ApiError> {
+    let query: ScreenshotQuery = parse_query(query)?;
+    let v2 = match query.format.as_deref() {
+        None | Some("v1") => false,
+        Some("v2") => true,
+        Some(other) => {
+            return Err(ApiError::BadRequest(format!(
+                "unsupported response format: {other}"
+            )));
         }
     };
 
-    let mut final_result = result;
-    {
-        let clients = state.clients.read().await;
-        for client in clients.values() {
-            final_result
-                .entry(client.name.clone())
-                .or_insert_with(Vec::new);
-        }
+    let (request_id, pending, timeout) = dispatch(&state, addr, &query).await?;
+    wait_finished(&pending, timeout + Duration::from_secs(1)).await;
+
+    let req = pending.lock().await;
+    let results = req.results();
+
+    if v2 {
+        return Ok(Json(ScreenshotResponseV2 {
+            request_id,
+            elapsed_ms: req.elapsed_ms(),
+            clients: results,
+        })
+        .into_response());
+    }
+
+    let images: HashMap<String, Vec<String>> = results
+        .into_iter()
+        .map(|(name, result)| (name, result.images))
+        .collect();
+    Ok(Json(images).into_response())
+}
+
+#[derive(Serialize)]
+struct RequestCreated {
+    request_id: String,
+    expected: usize,
+}
+
+async fn create_request_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    RawQuery(query): RawQuery,
+) -> Result<(StatusCode, Json<RequestCreated>), ApiError> {
+    let query: ScreenshotQuery = parse_query(query)?;
+    let (request_id, pending, _) = dispatch(&state, addr, &query).await?;
+    let expected = pending.lock().await.targets.len();
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(RequestCreated {
+            request_id,
+            expected,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct RequestStatusQuery {
+    wait: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RequestState {
+    Running,
+    Completed,
+}
+
+#[derive(Serialize)]
+struct RequestStatus {
+    request_id: String,
+    state: RequestState,
+    expected: usize,
+    received: usize,
+    elapsed_ms: u64,
+    clients: HashMap<String, ClientResult>,
+}
+
+async fn request_status_handler(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<RequestStatus>, ApiError> {
+    let query: RequestStatusQuery = parse_query(query)?;
+
+    let pending = {
+        let requests = state.pending_requests.read().await;
+        requests.get(&request_id).cloned()
     }
+    .ok_or_else(|| ApiError::NotFound(format!("unknown request: {request_id}")))?;
 
-    {
-        let mut requests = state.pending_requests.write().await;
-        requests.remove(&request_id);
+    if let Some(wait) = query.wait {
+        wait_finished(&pending, Duration::from_millis(wait).min(MAX_WAIT)).await;
     }
 
-    Json(final_result)
+    let req = pending.lock().await;
+    Ok(Json(RequestStatus {
+        request_id,
+        state: if req.is_finished() {
+            RequestState::Completed
+        } else {
+            RequestState::Running
+        },
+        expected: req.targets.len(),
+        received: req.received.len(),
+        elapsed_ms: req.elapsed_ms(),
+        clients: req.results(),
+    }))
+}
+
+#[derive(Serialize)]
+struct ClientSummary {
+    id: usize,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    machine_id: Option<String>,
+    protocol_version: u32,
+    app_version: String,
+    capabilities: Capabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info: Option<ClientInfo>,
+    addr: SocketAddr,
+    connected_at: DateTime<Utc>,
+    last_message_at: DateTime<Utc>,
+    screenshots_served: u64,
+}
+
+async fn clients_handler(State(state): State<Arc<AppState>>) -> Json<Vec<ClientSummary>> {
+    let clients = state.clients.read().await;
+    let mut summaries: Vec<ClientSummary> = clients
+        .iter()
+        .map(|(id, client)| ClientSummary {
+            id: *id,
+            name: client.name.clone(),
+            machine_id: client.machine_id.clone(),
+            protocol_version: client.protocol_version,
+            app_version: client.app_version.clone(),
+            capabilities: client.capabilities.clone(),
+            info: client.info.clone(),
+            addr: client.addr,
+            connected_at: client.connected_at,
+            last_message_at: client.last_message_at,
+            screenshots_served: client.screenshots_served,
+        })
+        .collect();
+    summaries.sort_by_key(|c| c.id);
+    Json(summaries)
+}
+
+#[derive(Deserialize)]
+struct EnrollmentTokenQuery {
+    ttl_mins: Option<i64>,
+}
+
+async fn create_enrollment_token_handler(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+) -> Result<(StatusCode, Json<EnrollmentToken>), ApiError> {
+    let query: EnrollmentTokenQuery = parse_query(query)?;
+    let ttl_mins = query.ttl_mins.unwrap_or(DEFAULT_TOKEN_TTL_MINS);
+    if ttl_mins <= 0 {
+        return Err(ApiError::BadRequest(
+            "ttl_mins must be positive".to_string(),
+        ));
+    }
+
+    let token = state
+        .credentials
+        .create_enrollment_token(chrono::Duration::minutes(ttl_mins))
+        .map_err(|e| ApiError::Internal(format!("failed to store enrollment token: {e}")))?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+async fn credentials_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<CredentialSummary>>, ApiError> {
+    state
+        .credentials
+        .list()
+        .map(Json)
+        .map_err(|e| ApiError::Internal(format!("failed to read client credentials: {e}")))
+}
+
+async fn set_credential_status(
+    state: &AppState,
+    name: &str,
+    status: CredentialStatus,
+) -> Result<Json<CredentialSummary>, ApiError> {
+    let summary = state
+        .credentials
+        .set_status(name, status)
+        .map_err(|e| ApiError::Internal(format!("failed to update client credentials: {e}")))?
+        .ok_or_else(|| ApiError::NotFound(format!("unknown client: {name}")))?;
+    tracing::info!("credential of {} is now {:?}", name, status);
+    Ok(Json(summary))
+}
+
+async fn approve_credential_handler(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<CredentialSummary>, ApiError> {
+    set_credential_status(&state, &name, CredentialStatus::Approved).await
+}
+
+async fn revoke_credential_handler(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<CredentialSummary>, ApiError> {
+    let summary = set_credential_status(&state, &name, CredentialStatus::Revoked).await?;
+    state.disconnect_credential(&name).await;
+    Ok(summary)
+}
+
+#[derive(Deserialize)]
+struct RotateSecretQuery {
+    grace_mins: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct SecretRotated {
+    secret_key: String,
+    previous_expires_at: Option<DateTime<Utc>>,
+}
+
+async fn rotate_secret_handler(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<SecretRotated>, ApiError> {
+    let query: RotateSecretQuery = parse_query(query)?;
+    let grace_mins = query.grace_mins.unwrap_or(DEFAULT_ROTATION_GRACE_MINS);
+    if grace_mins < 0 {
+        return Err(ApiError::BadRequest(
+            "grace_mins must not be negative".to_string(),
+        ));
+    }
+
+    let secrets = state
+        .secrets
+        .rotate(chrono::Duration::minutes(grace_mins))
+        .map_err(|e| ApiError::Internal(format!("failed to rotate secret key: {e}")))?;
+    tracing::info!(
+        "secret key rotated, previous key accepted for {} minutes",
+        grace_mins
+    );
+    state.push_rotated_secret().await;
+
+    Ok(Json(SecretRotated {
+        secret_key: secrets.current,
+        previous_expires_at: secrets.previous_expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    client: Vec<String>,
+    monitor: Option<u32>,
+    request_id: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    request_id: String,
+    client: String,
+    monitor: u32,
+    stitched: bool,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<ImageFormat>,
+    size: u64,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<ScreenshotMeta>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    images: Vec<HistoryEntry>,
+}
+
+const HISTORY_DEFAULT_LIMIT: usize = 50;
+const HISTORY_MAX_LIMIT: usize = 500;
+
+async fn history_handler(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let query: HistoryQuery = parse_query(query)?;
+    let limit = query
+        .limit
+        .unwrap_or(HISTORY_DEFAULT_LIMIT)
+        .min(HISTORY_MAX_LIMIT);
+    let patterns: Vec<WildMatch> = query.client.iter().map(|c| WildMatch::new(c)).collect();
+
+    let mut images: Vec<StoredImage> = state
+        .images
+        .snapshot()
+        .await
+        .into_iter()
+        .filter(|img| patterns.is_empty() || patterns.iter().any(|p| p.matches(&img.client)))
+        .filter(|img| {
+            query
+                .monitor
+                .is_none_or(|m| !img.stitched && img.monitor == m)
+        })
+        .filter(|img| {
+            query
+                .request_id
+                .as_ref()
+                .is_none_or(|id| &img.request_id == id)
+        })
+        .filter(|img| query.since.is_none_or(|t| img.created_at >= t))
+        .filter(|img| query.until.is_none_or(|t| img.created_at < t))
+        .collect();
+    images.sort_by_key(|img| std::cmp::Reverse(img.created_at));
+
+    let total = images.len();
+    let images = images
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|img| HistoryEntry {
+            url: state.url_signer.sign(&img.filename),
+            format: img.format(),
+            request_id: img.request_id,
+            client: img.client,
+            monitor: img.monitor,
+            stitched: img.stitched,
+            size: img.size,
+            created_at: img.created_at,
+            meta: img.meta,
+        })
+        .collect();
+
+    Ok(Json(HistoryResponse {
+        total,
+        offset: query.offset,
+        limit,
+        images,
+    }))
+}
+
+async fn events_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (replay, rx) = state.events.subscribe(last_id);
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("event subscriber lagged, skipped {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    // End the stream on shutdown so that it does not hold up the server.
+    let live = live.take_until(state.shutdown.clone().cancelled_owned());
+
+    let events = stream::iter(replay).chain(live).map(|event| {
+        Ok(SseEvent::default()
+            .id(event.id.to_string())
+            .event(event.event.kind())
+            .json_data(&*event)
+            .unwrap())
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
 }
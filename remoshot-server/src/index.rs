@@ -0,0 +1,309 @@
+use chrono::{DateTime, Utc};
+use remoshot_common::{ImageFormat, ScreenshotMeta};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredImage {
+    pub request_id: String,
+    pub client: String,
+    /// For stitched images the first of the composed monitors.
+    pub monitor: u32,
+    /// Composed of several monitors by the client.
+    #[serde(default)]
+    pub stitched: bool,
+    pub filename: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+    /// As reported by the client; `None` for images of older clients and
+    /// for files adopted from the image directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScreenshotMeta>,
+}
+
+impl StoredImage {
+    pub fn format(&self) -> Option<ImageFormat> {
+        let (_, extension) = self.filename.rsplit_once('.')?;
+        ImageFormat::from_extension(extension)
+    }
+}
+
+/// Metadata for every image kept in the image directory, persisted as a
+/// journal of JSON lines. New images are appended; the journal is rewritten
+/// whenever images are removed.
+pub struct ImageIndex {
+    image_dir: PathBuf,
+    journal_path: PathBuf,
+    inner: Mutex<IndexInner>,
+}
+
+struct IndexInner {
+    images: Vec<StoredImage>,
+    journal: File,
+}
+
+impl ImageIndex {
+    pub fn open(image_dir: PathBuf, journal_path: PathBuf) -> io::Result<Self> {
+        let mut images = load_journal(&journal_path)?;
+        reconcile(&image_dir, &mut images)?;
+        images.sort_by_key(|img| img.created_at);
+
+        let journal = write_journal(&journal_path, &images)?;
+        tracing::info!(
+            "loaded image index with {} images from {:?}",
+            images.len(),
+            journal_path
+        );
+
+        Ok(Self {
+            image_dir,
+            journal_path,
+            inner: Mutex::new(IndexInner { images, journal }),
+        })
+    }
+
+    pub fn path_of(&self, image: &StoredImage) -> PathBuf {
+        self.image_dir.join(&image.filename)
+    }
+
+    pub async fn insert(&self, image: StoredImage) {
+        let mut inner = self.inner.lock().await;
+        let line = serde_json::to_string(&image).unwrap();
+        if let Err(e) = writeln!(inner.journal, "{line}") {
+            tracing::warn!("failed to append to image index: {}", e);
+        }
+        inner.images.push(image);
+    }
+
+    /// Makes sure every appended record has reached the disk.
+    pub async fn flush(&self) -> io::Result<()> {
+        let inner = self.inner.lock().await;
+        inner.journal.sync_data()
+    }
+
+    pub async fn snapshot(&self) -> Vec<StoredImage> {
+        self.inner.lock().await.images.clone()
+    }
+
+    /// Removes records created before `cutoff` from the index and returns
+    /// them so that the caller can delete the files.
+    pub async fn take_expired(&self, cutoff: DateTime<Utc>) -> Vec<StoredImage> {
+        let mut inner = self.inner.lock().await;
+        let (expired, kept): (Vec<_>, Vec<_>) = inner
+            .images
+            .drain(..)
+            .partition(|img| img.created_at < cutoff);
+        inner.images = kept;
+
+        if !expired.is_empty() {
+            match write_journal(&self.journal_path, &inner.images) {
+                Ok(journal) => inner.journal = journal,
+                Err(e) => tracing::warn!("failed to rewrite image index: {}", e),
+            }
+        }
+
+        expired
+    }
+}
+
+fn load_journal(path: &Path) -> io::Result<Vec<StoredImage>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut images = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<StoredImage>(&line) {
+            Ok(img) => images.push(img),
+            Err(e) => tracing::warn!("skipping invalid image index line {}: {}", i + 1, e),
+        }
+    }
+    Ok(images)
+}
+
+fn write_journal(path: &Path, images: &[StoredImage]) -> io::Result<File> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        for img in images {
+            writeln!(tmp, "{}", serde_json::to_string(img).unwrap())?;
+        }
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Drops records whose file is gone and adopts image files that have no
+/// record. Anything else is left alone, since the image directory may be
+/// shared with other files such as the server's keys.
+fn reconcile(image_dir: &Path, images: &mut Vec<StoredImage>) -> io::Result<()> {
+    let mut on_disk = HashSet::new();
+    for entry in fs::read_dir(image_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            on_disk.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    images.retain(|img| {
+        let present = on_disk.contains(&img.filename);
+        if !present {
+            tracing::warn!("dropping index record for missing image {}", img.filename);
+        }
+        present
+    });
+
+    let known: HashSet<String> = images.iter().map(|img| img.filename.clone()).collect();
+    for filename in on_disk.difference(&known) {
+        let path = image_dir.join(filename);
+        match adopt(&path, filename) {
+            Some(img) => {
+                tracing::info!("adopted orphaned image {}", filename);
+                images.push(img);
+            }
+            None => {
+                tracing::warn!("ignoring unrecognized file in image directory: {:?}", path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn adopt(path: &Path, filename: &str) -> Option<StoredImage> {
+    let (request_id, client, monitor, stitched) = parse_filename(filename)?;
+    let meta = fs::metadata(path).ok()?;
+    let created_at = meta
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+
+    Some(StoredImage {
+        request_id,
+        client,
+        monitor,
+        stitched,
+        filename: filename.to_string(),
+        size: meta.len(),
+        created_at,
+        meta: None,
+    })
+}
+
+/// Builds the `<client>` part of an image filename: client names are chosen
+/// by the clients, so everything but ASCII letters, digits, `-`, `_` and `.`
+/// is replaced.
+pub fn filename_component(client: &str) -> String {
+    client
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Names the file of a stored screenshot, as parsed by [`parse_filename`].
+pub fn image_filename(
+    request_id: &str,
+    client: &str,
+    monitor: u32,
+    stitched: bool,
+    timestamp_millis: i64,
+    format: ImageFormat,
+) -> String {
+    format!(
+        "{}_{}_{}{}_{}.{}",
+        request_id,
+        filename_component(client),
+        if stitched { STITCHED_PREFIX } else { "" },
+        monitor,
+        timestamp_millis,
+        format.extension()
+    )
+}
+
+/// Marks the monitor of stitched images in their filenames.
+const STITCHED_PREFIX: &str = "s";
+
+/// Parses `<request_id>_<client>_<monitor>_<timestamp>.<ext>`, where
+/// `<monitor>` of stitched images is the first monitor prefixed with `s`.
+fn parse_filename(filename: &str) -> Option<(String, String, u32, bool)> {
+    let (stem, extension) = filename.rsplit_once('.')?;
+    ImageFormat::from_extension(extension)?;
+    let (request_id, rest) = stem.split_once('_')?;
+    let (rest, timestamp) = rest.rsplit_once('_')?;
+    let (client, monitor) = rest.rsplit_once('_')?;
+    timestamp.parse::<i64>().ok()?;
+    let (monitor, stitched) = match monitor.strip_prefix(STITCHED_PREFIX) {
+        Some(monitor) => (monitor, true),
+        None => (monitor, false),
+    };
+
+    Some((
+        request_id.to_string(),
+        client.to_string(),
+        monitor.parse().ok()?,
+        stitched,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_component_keeps_names_inside_the_image_directory() {
+        assert_eq!(filename_component("deviceA"), "deviceA");
+        assert_eq!(filename_component("my_pc-2.lan"), "my_pc-2.lan");
+        assert_eq!(filename_component("../../etc/passwd"), "..-..-etc-passwd");
+        assert_eq!(filename_component("a\\b:c"), "a-b-c");
+        assert_eq!(filename_component("小明的电脑"), "-----");
+    }
+
+    #[test]
+    fn image_filenames_round_trip() {
+        for client in ["deviceA", "my_pc_2", "a.b", "../x", "小明"] {
+            for stitched in [false, true] {
+                let filename = image_filename(
+                    "req-1",
+                    client,
+                    3,
+                    stitched,
+                    1735718400000,
+                    ImageFormat::Png,
+                );
+                assert!(!filename.contains('/'));
+                assert_eq!(
+                    parse_filename(&filename),
+                    Some(("req-1".to_string(), filename_component(client), 3, stitched))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unrelated_filenames_are_not_parsed() {
+        assert_eq!(parse_filename("notes.txt"), None);
+        assert_eq!(parse_filename("req_client_0_123.txt"), None);
+        assert_eq!(parse_filename("req_client_x_123.png"), None);
+        assert_eq!(parse_filename("req_client_s_123.png"), None);
+        assert_eq!(parse_filename("req_client_0_later.png"), None);
+        assert_eq!(parse_filename("req_0_123.png"), None);
+        assert_eq!(parse_filename(".hidden"), None);
+    }
+}
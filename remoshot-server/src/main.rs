@@ -1,33 +1,131 @@
+mod auth;
 mod cleanup;
+mod config;
+mod credentials;
+mod events;
 mod http;
+mod index;
 mod secret;
+mod signing;
 mod state;
+mod tls;
 mod ws;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::io::IsTerminal;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::EnvFilter;
 
+/// How long clients wait before reconnecting after the server shut down,
+/// long enough for a service manager to restart it.
+const RECONNECT_AFTER: Duration = Duration::from_secs(5);
+
+/// Key for signed image URLs, kept next to the secret key.
+const SIGNING_KEY_FILE: &str = "signing.key";
+
 #[derive(Parser, Debug)]
 #[command(name = "remoshot-server", about = "RemoShot screenshot server")]
 struct Args {
-    #[arg(long)]
-    ws_port: Option<u16>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with any of the settings below; flags and environment
+    /// variables take precedence over it
+    #[arg(long, env = "REMOSHOT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Exit with an error instead of prompting for missing settings
+    #[arg(long, env = "REMOSHOT_NON_INTERACTIVE")]
+    non_interactive: bool,
+
+    #[command(flatten)]
+    options: config::Options,
+}
 
-    #[arg(long)]
-    http_addr: Option<String>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a one-time token that lets a new client enroll
+    EnrollToken {
+        /// Validity of the token in minutes
+        #[arg(long, default_value_t = credentials::DEFAULT_TOKEN_TTL_MINS)]
+        ttl: i64,
+    },
+    /// List enrolled clients and the state of their credentials
+    Clients,
+    /// Approve an enrolled client
+    Approve { name: String },
+    /// Revoke the credential of a client; other clients are not affected
+    Revoke { name: String },
+    /// Replace the shared secret key, accepting the old one for a grace period
+    RotateSecret {
+        /// Minutes during which clients may still use the old secret key
+        #[arg(long, default_value_t = secret::DEFAULT_ROTATION_GRACE_MINS)]
+        grace: i64,
+    },
+}
+
+fn run_command(
+    command: Command,
+    store: &credentials::CredentialStore,
+    secrets: &secret::SecretStore,
+) {
+    match command {
+        Command::EnrollToken { ttl } => {
+            let token = store
+                .create_enrollment_token(chrono::Duration::minutes(ttl))
+                .expect("failed to store enrollment token");
+            println!("{} (expires {})", token.token, token.expires_at);
+        }
+        Command::Clients => {
+            for cred in store.list().expect("failed to read client credentials") {
+                println!(
+                    "{}\t{:?}\tenrolled {}",
+                    cred.name, cred.status, cred.enrolled_at
+                );
+            }
+        }
+        Command::Approve { name } => {
+            set_credential_status(store, &name, credentials::CredentialStatus::Approved)
+        }
+        Command::Revoke { name } => {
+            set_credential_status(store, &name, credentials::CredentialStatus::Revoked)
+        }
+        Command::RotateSecret { grace } => {
+            secrets.load_or_generate();
+            let rotated = secrets
+                .rotate(chrono::Duration::minutes(grace))
+                .expect("failed to rotate secret key");
+            println!("{}", rotated.current);
+            if let Some(expires_at) = rotated.previous_expires_at {
+                println!("previous secret key accepted until {expires_at}");
+            }
+        }
+    }
+}
 
-    #[arg(long)]
-    retention: Option<u64>,
+fn set_credential_status(
+    store: &credentials::CredentialStore,
+    name: &str,
+    status: credentials::CredentialStatus,
+) {
+    match store
+        .set_status(name, status)
+        .expect("failed to update client credentials")
+    {
+        Some(cred) => println!("{}: {:?}", cred.name, cred.status),
+        None => {
+            eprintln!("unknown client: {name}");
+            std::process::exit(1);
+        }
+    }
 }
 
-fn prompt(msg: &str) -> String {
-    dialoguer::Input::<String>::new()
-        .with_prompt(msg)
-        .interact_text()
-        .unwrap()
+fn exit_with(error: config::ConfigError) -> ! {
+    eprintln!("error: {error}");
+    std::process::exit(2);
 }
 
 #[tokio::main]
@@ -40,33 +138,70 @@ async fn main() {
 
     let args = Args::parse();
 
-    let secret_key = secret::load_or_generate_secret();
+    let options = match &args.config {
+        Some(path) => config::Options::load_file(path).map(|file| args.options.or(file)),
+        None => Ok(args.options),
+    }
+    .unwrap_or_else(|e| exit_with(e));
+
+    let credentials = credentials::CredentialStore::new(options.clients_file());
+    let secrets = secret::SecretStore::new(options.secret_key_file());
+    if let Some(command) = args.command {
+        run_command(command, &credentials, &secrets);
+        return;
+    }
+
+    // Without a terminal (e.g. under systemd) a prompt would block forever.
+    let interactive = !args.non_interactive && std::io::stdin().is_terminal();
+    let config =
+        config::ServerConfig::resolve(options, interactive).unwrap_or_else(|e| exit_with(e));
+
+    let secret_key = secrets.load_or_generate();
     tracing::info!("Server SecretKey: {}", secret_key);
 
-    let ws_port: u16 = args.ws_port.unwrap_or_else(|| {
-        prompt("WebSocket port for client connections")
-            .parse()
-            .expect("invalid port")
-    });
+    std::fs::create_dir_all(&config.image_dir).expect("failed to create images directory");
 
-    let http_addr_str = args
-        .http_addr
-        .unwrap_or_else(|| prompt("HTTP listen address (e.g. 127.0.0.1:8113)"));
-    let http_addr: SocketAddr = http_addr_str.parse().expect("invalid HTTP address");
+    let url_signer = signing::UrlSigner::load_or_generate(
+        &config.state_dir().join(SIGNING_KEY_FILE),
+        config.image_url_ttl,
+    )
+    .expect("failed to load URL signing key");
 
-    let retention_mins: u64 = args.retention.unwrap_or_else(|| {
-        prompt("Screenshot retention time in minutes")
-            .parse()
-            .expect("invalid retention time")
-    });
+    let images = index::ImageIndex::open(config.image_dir.clone(), config.index_file.clone())
+        .expect("failed to load image index");
 
-    let image_dir = PathBuf::from("images");
-    std::fs::create_dir_all(&image_dir).expect("failed to create images directory");
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let tls_files = match &config.tls {
+        Some(config::TlsSource::Files { cert, key }) => Some(tls::TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        }),
+        Some(config::TlsSource::SelfSigned { names }) => Some(
+            tls::self_signed(config.state_dir(), names)
+                .expect("failed to create self-signed certificate"),
+        ),
+        None => None,
+    };
+    let tls_config = match &tls_files {
+        Some(files) => {
+            let fingerprint =
+                tls::fingerprint(&files.cert).expect("failed to read TLS certificate");
+            tracing::info!("TLS certificate SHA-256 fingerprint: {}", fingerprint);
+            Some(
+                tls::load(files)
+                    .await
+                    .expect("failed to load TLS certificate"),
+            )
+        }
+        None => None,
+    };
 
     let state = Arc::new(state::AppState::new(
-        retention_mins,
-        image_dir.clone(),
-        secret_key,
+        config,
+        images,
+        credentials,
+        secrets,
+        url_signer,
     ));
 
     let cleanup_state = state.clone();
@@ -75,13 +210,76 @@ async fn main() {
     });
 
     let ws_state = state.clone();
-    let ws_addr: SocketAddr = format!("0.0.0.0:{ws_port}").parse().unwrap();
+    let ws_tls = tls_config.clone();
+    let ws_addr: SocketAddr = format!("0.0.0.0:{}", state.config.ws_port).parse().unwrap();
     tokio::spawn(async move {
-        ws::run_ws_server(ws_addr, ws_state).await;
+        ws::run_ws_server(ws_addr, ws_state, ws_tls).await;
     });
 
+    if state.config.api_tokens.is_empty() {
+        tracing::warn!("no API tokens configured, the HTTP API is unauthenticated");
+    }
+    if state.config.allow_legacy_clients {
+        tracing::warn!(
+            "accepting clients that send no hello; they cannot verify that they talk to this server"
+        );
+    }
+
     tracing::info!("WebSocket server listening on {}", ws_addr);
-    tracing::info!("HTTP server listening on {}", http_addr);
+    tracing::info!("HTTP server listening on {}", state.config.http_addr);
+
+    let http_state = state.clone();
+    let image_dir = state.config.image_dir.clone();
+    let http_addr = state.config.http_addr;
+    let http = tokio::spawn(async move {
+        http::run_http_server(http_addr, http_state, image_dir, tls_config).await;
+    });
+
+    shutdown_signal().await;
+    tracing::info!("shutting down, waiting for running requests...");
+    state.shutdown.cancel();
+
+    // Clients stay connected until the requests they are answering are done.
+    let drained = tokio::time::timeout(state.config.shutdown_timeout, async {
+        let _ = http.await;
+        state.wait_for_pending_requests().await;
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!("shutdown timeout reached, abandoning running requests");
+    }
+
+    state.disconnect_all(RECONNECT_AFTER).await;
+    state.connections.close();
+    // Connections still in the handshake are not registered and not told;
+    // waiting longer than the clients do before reconnecting is pointless.
+    let _ = tokio::time::timeout(RECONNECT_AFTER, state.connections.wait()).await;
+
+    if let Err(e) = state.images.flush().await {
+        tracing::warn!("failed to flush image index: {}", e);
+    }
+    tracing::info!("shutdown complete");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    http::run_http_server(http_addr, state, image_dir).await;
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
@@ -1,30 +1,132 @@
+use chrono::{DateTime, Utc};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-fn secret_key_path() -> PathBuf {
-    PathBuf::from("secret.key")
+/// How long the previous secret keeps working after a rotation unless the
+/// administrator asks otherwise.
+pub const DEFAULT_ROTATION_GRACE_MINS: i64 = 24 * 60;
+
+/// The secret that was current before the last rotation, still accepted
+/// until `expires_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PreviousSecret {
+    key: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Secrets {
+    pub current: String,
+    /// Only set while the grace period of the last rotation is running.
+    pub previous: Option<String>,
+    pub previous_expires_at: Option<DateTime<Utc>>,
 }
 
-pub fn load_or_generate_secret() -> String {
-    let path = secret_key_path();
+/// The shared secret key in `secret.key` and, during a rotation, the
+/// previous one next to it. Like the client credentials, both files are
+/// re-read on every use so that a rotation made through the CLI reaches a
+/// running server.
+pub struct SecretStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl SecretStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn previous_path(&self) -> PathBuf {
+        self.path.with_extension("previous")
+    }
+
+    pub fn load_or_generate(&self) -> String {
+        let _guard = self.lock.lock().unwrap();
+
+        if let Ok(secret) = fs::read_to_string(&self.path) {
+            let secret = secret.trim();
+            if !secret.is_empty() {
+                return secret.to_string();
+            }
+        }
 
-    if let Ok(secret) = fs::read_to_string(&path) {
-        let secret = secret.trim();
-        if !secret.is_empty() {
-            return secret.to_string();
+        let secret = generate_secret();
+        if let Err(e) = write_private(&self.path, &secret) {
+            tracing::warn!("failed to save secret key: {}", e);
         }
+
+        secret
+    }
+
+    pub fn load(&self) -> io::Result<Secrets> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    /// Replaces the current secret with a new one and keeps accepting the
+    /// old one for `grace`.
+    pub fn rotate(&self, grace: chrono::Duration) -> io::Result<Secrets> {
+        let _guard = self.lock.lock().unwrap();
+        let old = self.read()?;
+
+        let previous = PreviousSecret {
+            key: old.current,
+            expires_at: Utc::now() + grace,
+        };
+        write_private(
+            &self.previous_path(),
+            serde_json::to_string_pretty(&previous).unwrap(),
+        )?;
+        write_private(&self.path, generate_secret())?;
+
+        self.read()
     }
 
-    let secret = generate_secret();
-    if let Err(e) = fs::write(&path, &secret) {
-        tracing::warn!("failed to save secret key: {}", e);
+    fn read(&self) -> io::Result<Secrets> {
+        let current = fs::read_to_string(&self.path)?.trim().to_string();
+        let previous = match fs::read_to_string(self.previous_path()) {
+            Ok(data) => {
+                Some(serde_json::from_str::<PreviousSecret>(&data).map_err(io::Error::other)?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        }
+        .filter(|p| p.expires_at > Utc::now());
+
+        Ok(Secrets {
+            current,
+            previous_expires_at: previous.as_ref().map(|p| p.expires_at),
+            previous: previous.map(|p| p.key),
+        })
     }
+}
+
+/// Writes a file only the server's user may read.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    io::Write::write_all(&mut create_private(path)?, contents.as_ref())
+}
 
-    secret
+/// Creates or truncates a file only the server's user may read. The mode of
+/// an existing file is tightened as well.
+pub fn create_private(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    Ok(file)
 }
 
-fn generate_secret() -> String {
+pub fn generate_secret() -> String {
     let mut rng = rand::thread_rng();
     let bytes: [u8; 32] = rng.r#gen();
     hex::encode(bytes)
@@ -0,0 +1,114 @@
+use chrono::Utc;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+
+const FILENAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+
+/// Signs image URLs with an expiry so that they can be shared without
+/// exposing the whole image directory. The key only ever leaves the server
+/// as signatures; unlike the shared secret, clients never hold it.
+pub struct UrlSigner {
+    key: String,
+    ttl: Duration,
+}
+
+impl UrlSigner {
+    /// Uses the key in `path`, generating it on first start so that signed
+    /// URLs stay valid across restarts.
+    pub fn load_or_generate(path: &Path, ttl: Duration) -> io::Result<Self> {
+        let key = match std::fs::read_to_string(path) {
+            Ok(key) if !key.trim().is_empty() => key.trim().to_string(),
+            Ok(_) => Self::generate(path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::generate(path)?,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { key, ttl })
+    }
+
+    fn generate(path: &Path) -> io::Result<String> {
+        let key = crate::secret::generate_secret();
+        crate::secret::write_private(path, &key)?;
+        tracing::info!("generated URL signing key {:?}", path);
+        Ok(key)
+    }
+
+    pub fn sign(&self, filename: &str) -> String {
+        let expires = Utc::now().timestamp() + self.ttl.as_secs() as i64;
+        let sig = self.signature(filename, expires);
+        let path = utf8_percent_encode(filename, FILENAME);
+        format!("/images/{path}?expires={expires}&sig={sig}")
+    }
+
+    pub fn verify(&self, filename: &str, expires: i64, sig: &str) -> bool {
+        if expires < Utc::now().timestamp() {
+            return false;
+        }
+        let expected = self.signature(filename, expires);
+        expected.as_bytes().ct_eq(sig.as_bytes()).into()
+    }
+
+    fn signature(&self, filename: &str, expires: i64) -> String {
+        remoshot_common::compute_hmac(&self.key, &format!("{filename}:{expires}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            key: "test-key".to_string(),
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    fn query(url: &str) -> (i64, String) {
+        let (_, query) = url.split_once('?').unwrap();
+        let (expires, sig) = query.split_once('&').unwrap();
+        (
+            expires.strip_prefix("expires=").unwrap().parse().unwrap(),
+            sig.strip_prefix("sig=").unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn signed_url_verifies() {
+        let signer = signer();
+        let url = signer.sign("a b.png");
+        assert!(url.starts_with("/images/a%20b.png?"));
+        let (expires, sig) = query(&url);
+        assert!(signer.verify("a b.png", expires, &sig));
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let signer = signer();
+        let (expires, sig) = query(&signer.sign("a.png"));
+        assert!(!signer.verify("b.png", expires, &sig));
+        assert!(!signer.verify("a.png", expires + 1, &sig));
+        assert!(!signer.verify("a.png", expires, &sig[1..]));
+        assert!(!signer.verify("a.png", expires, ""));
+    }
+
+    #[test]
+    fn expired_signature_is_rejected() {
+        let signer = signer();
+        let expires = Utc::now().timestamp() - 1;
+        let sig = signer.signature("a.png", expires);
+        assert!(!signer.verify("a.png", expires, &sig));
+    }
+
+    #[test]
+    fn signatures_depend_on_the_key() {
+        let (expires, sig) = query(&signer().sign("a.png"));
+        let other = UrlSigner {
+            key: "other-key".to_string(),
+            ttl: Duration::from_secs(60),
+        };
+        assert!(!other.verify("a.png", expires, &sig));
+    }
+}
@@ -1,48 +1,260 @@
 use chrono::{DateTime, Utc};
+use remoshot_common::{
+    AuthRejectReason, Capabilities, CaptureErrorReason, CaptureOptions, ClientInfo, ImageFormat,
+    ScreenshotMeta, features,
+};
+use serde::Serialize;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock, broadcast, oneshot};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock, watch};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use wildmatch::WildMatch;
+
+use crate::config::{NameCollision, ServerConfig};
+use crate::credentials::{CredentialStatus, CredentialStore};
+use crate::events::{EventBus, ServerEvent};
+use crate::index::ImageIndex;
+use crate::secret::SecretStore;
+use crate::signing::UrlSigner;
 
 pub struct ConnectedClient {
     pub name: String,
+    /// Stable ID the client generated for itself, if it sent one.
+    pub machine_id: Option<String>,
+    /// From the client's hello.
+    pub protocol_version: u32,
+    pub app_version: String,
+    pub capabilities: Capabilities,
+    /// The machine's latest [`remoshot_common::ClientMessage::ClientInfo`] report.
+    pub info: Option<ClientInfo>,
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub last_message_at: DateTime<Utc>,
+    pub screenshots_served: u64,
     pub tx: tokio::sync::mpsc::UnboundedSender<String>,
+    /// Cancelled to drop the connection, e.g. when its credential is revoked.
+    pub kick: CancellationToken,
+    /// The shared secret key the client authenticated with; `None` for
+    /// clients using their own key.
+    pub shared_secret: Option<String>,
+    /// Nonces of the handshake, which secret updates are bound to. Clients
+    /// speaking protocol version 1 send no nonce of their own.
+    pub server_nonce: String,
+    pub client_nonce: String,
+    /// Name of the per-client credential the client authenticated with,
+    /// which differs from `name` when a collision was resolved by suffixing.
+    pub credential: Option<String>,
+}
+
+impl ConnectedClient {
+    /// Whether the client announced `feature` in its hello.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.capabilities.has_feature(feature)
+    }
+
+    /// `options` adjusted to what the client can capture: an image format it
+    /// did not announce is replaced by one it did, and clients that cannot
+    /// stitch send one image per monitor.
+    fn adapt_options(&self, options: &CaptureOptions) -> CaptureOptions {
+        let mut options = options.clone();
+        let caps = &self.capabilities;
+        if !caps.supports_format(options.format) {
+            let fallback = ImageFormat::ALL
+                .into_iter()
+                .find(|f| caps.supports_format(*f))
+                .unwrap_or_default();
+            tracing::info!(
+                "{} cannot capture {:?}, requesting {:?} instead",
+                self.name,
+                options.format,
+                fallback
+            );
+            options.format = fallback;
+        }
+        if options.stitch && !self.supports(features::STITCH) {
+            tracing::info!(
+                "{} cannot stitch, requesting one image per monitor",
+                self.name
+            );
+            options.stitch = false;
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientStatus {
+    Pending,
+    Ok,
+    Timeout,
+    CaptureFailed,
+    WriteFailed,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitor: Option<u32>,
+    pub reason: CaptureErrorReason,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientResult {
+    pub status: ClientStatus,
+    pub elapsed_ms: u64,
+    /// URLs of `screenshots`, kept for consumers that only want those.
+    pub images: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub screenshots: Vec<ImageDetails>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<CaptureError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageDetails {
+    pub url: String,
+    pub monitor: u32,
+    pub format: ImageFormat,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScreenshotMeta>,
 }
 
 pub struct PendingRequest {
-    pub expected: usize,
-    pub received: HashMap<String, Vec<String>>,
-    pub notify: Option<oneshot::Sender<HashMap<String, Vec<String>>>>,
+    /// Names of the targeted clients by connection ID, so that a connection
+    /// that replaced another one under the same name is not mistaken for it.
+    pub targets: HashMap<usize, String>,
+    pub started_at: Instant,
+    pub finished_at: Option<Instant>,
+    pub received: HashMap<String, ClientResult>,
+    done: watch::Sender<bool>,
 }
 
-pub struct StoredImage {
-    pub path: PathBuf,
-    pub created_at: DateTime<Utc>,
+impl PendingRequest {
+    pub fn new(targets: HashMap<usize, String>) -> Self {
+        let (done, _) = watch::channel(false);
+        Self {
+            targets,
+            started_at: Instant::now(),
+            finished_at: None,
+            received: HashMap::new(),
+            done,
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        let end = self.finished_at.unwrap_or_else(Instant::now);
+        end.duration_since(self.started_at).as_millis() as u64
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.done.subscribe()
+    }
+
+    pub fn finish(&mut self) {
+        if self.finished_at.is_none() {
+            self.finished_at = Some(Instant::now());
+            self.done.send_replace(true);
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        client_name: &str,
+        status: ClientStatus,
+        screenshots: Vec<ImageDetails>,
+        errors: Vec<CaptureError>,
+    ) {
+        if self.is_finished() {
+            tracing::warn!("late response from {} ignored", client_name);
+            return;
+        }
+
+        let result = ClientResult {
+            status,
+            elapsed_ms: self.elapsed_ms(),
+            images: screenshots.iter().map(|s| s.url.clone()).collect(),
+            screenshots,
+            errors,
+        };
+        self.received.insert(client_name.to_string(), result);
+
+        if self.received.len() >= self.targets.len() {
+            self.finish();
+        }
+    }
+
+    /// Results for every targeted client. Clients that have not answered are
+    /// reported as pending while the request is running and as timed out
+    /// once it has finished.
+    pub fn results(&self) -> HashMap<String, ClientResult> {
+        let missing = if self.is_finished() {
+            ClientStatus::Timeout
+        } else {
+            ClientStatus::Pending
+        };
+
+        let mut results = self.received.clone();
+        for name in self.targets.values() {
+            results.entry(name.clone()).or_insert_with(|| ClientResult {
+                status: missing,
+                elapsed_ms: self.elapsed_ms(),
+                images: Vec::new(),
+                screenshots: Vec::new(),
+                errors: Vec::new(),
+            });
+        }
+        results
+    }
 }
 
 pub struct AppState {
     pub clients: RwLock<HashMap<usize, ConnectedClient>>,
     pub next_client_id: Mutex<usize>,
     pub pending_requests: RwLock<HashMap<String, Arc<Mutex<PendingRequest>>>>,
-    pub stored_images: Mutex<Vec<StoredImage>>,
-    pub retention_mins: u64,
-    pub image_dir: PathBuf,
-    pub secret_key: String,
-    pub _shutdown_tx: broadcast::Sender<()>,
+    pub images: ImageIndex,
+    pub events: EventBus,
+    pub config: ServerConfig,
+    pub url_signer: UrlSigner,
+    pub credentials: CredentialStore,
+    pub secrets: SecretStore,
+    /// Cancelled once the server starts shutting down.
+    pub shutdown: CancellationToken,
+    /// Client connection tasks, awaited on shutdown so that the shutdown
+    /// notice reaches every client.
+    pub connections: TaskTracker,
 }
 
 impl AppState {
-    pub fn new(retention_mins: u64, image_dir: PathBuf, secret_key: String) -> Self {
-        let (shutdown_tx, _) = broadcast::channel(1);
+    pub fn new(
+        config: ServerConfig,
+        images: ImageIndex,
+        credentials: CredentialStore,
+        secrets: SecretStore,
+        url_signer: UrlSigner,
+    ) -> Self {
         Self {
             clients: RwLock::new(HashMap::new()),
             next_client_id: Mutex::new(0),
             pending_requests: RwLock::new(HashMap::new()),
-            stored_images: Mutex::new(Vec::new()),
-            retention_mins,
-            image_dir,
-            secret_key,
-            _shutdown_tx: shutdown_tx,
+            images,
+            events: EventBus::new(),
+            url_signer,
+            config,
+            credentials,
+            secrets,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
         }
     }
 
@@ -53,14 +265,87 @@ impl AppState {
         current
     }
 
+    /// Registers a connection under a name no other connection uses,
+    /// resolving a collision according to the configured policy, and returns
+    /// that name. An earlier connection of the same machine is always
+    /// replaced since it can only be a stale one.
     pub async fn register_client(
         &self,
         id: usize,
-        name: String,
-        tx: tokio::sync::mpsc::UnboundedSender<String>,
-    ) {
+        mut client: ConnectedClient,
+    ) -> Result<String, AuthRejectReason> {
         let mut clients = self.clients.write().await;
-        clients.insert(id, ConnectedClient { name, tx });
+
+        if client.machine_id.is_some() {
+            let stale: Vec<usize> = clients
+                .iter()
+                .filter(|(_, c)| c.machine_id == client.machine_id)
+                .map(|(id, _)| *id)
+                .collect();
+            for stale_id in stale {
+                if let Some(old) = clients.remove(&stale_id) {
+                    tracing::info!("{} reconnected, dropping connection {}", old.name, stale_id);
+                    old.kick.cancel();
+                }
+            }
+        }
+
+        let taken = clients
+            .iter()
+            .find(|(_, c)| c.name == client.name)
+            .map(|(id, _)| *id);
+        if let Some(old_id) = taken {
+            match self.config.name_collision {
+                NameCollision::Reject => return Err(AuthRejectReason::NameInUse),
+                NameCollision::Replace => {
+                    tracing::info!(
+                        "another machine connected as {}, dropping connection {}",
+                        client.name,
+                        old_id
+                    );
+                    if let Some(old) = clients.remove(&old_id) {
+                        old.kick.cancel();
+                    }
+                }
+                NameCollision::Suffix => {
+                    let name = (2..)
+                        .map(|n| format!("{}-{}", client.name, n))
+                        .find(|candidate| !clients.values().any(|c| c.name == *candidate))
+                        .unwrap();
+                    tracing::info!(
+                        "{} is already connected, registering as {}",
+                        client.name,
+                        name
+                    );
+                    client.name = name;
+                }
+            }
+        }
+
+        let name = client.name.clone();
+        clients.insert(id, client);
+        Ok(name)
+    }
+
+    pub async fn touch_client(&self, id: usize) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(&id) {
+            client.last_message_at = Utc::now();
+        }
+    }
+
+    pub async fn set_client_info(&self, id: usize, info: ClientInfo) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(&id) {
+            client.info = Some(info);
+        }
+    }
+
+    pub async fn record_screenshots_served(&self, id: usize, count: usize) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(&id) {
+            client.screenshots_served += count as u64;
+        }
     }
 
     pub async fn unregister_client(&self, id: usize) {
@@ -68,27 +353,242 @@ impl AppState {
         clients.remove(&id);
     }
 
-    pub async fn broadcast_screenshot_request(&self, request_id: &str) -> usize {
+    /// Drops every connection authenticated with the credential of `name`.
+    pub async fn disconnect_credential(&self, name: &str) {
         let clients = self.clients.read().await;
-        let msg = serde_json::to_string(&remoshot_common::ServerMessage::ScreenshotRequest {
-            request_id: request_id.to_string(),
+        for client in clients
+            .values()
+            .filter(|c| c.credential.as_deref() == Some(name))
+        {
+            client.kick.cancel();
+        }
+    }
+
+    /// Drops connections whose credential is no longer approved, catching
+    /// revocations made through the CLI while the server is running.
+    pub async fn disconnect_unapproved(&self) {
+        let credentials = match self.credentials.list() {
+            Ok(list) => list,
+            Err(e) => {
+                tracing::warn!("failed to read client credentials: {}", e);
+                return;
+            }
+        };
+        let clients = self.clients.read().await;
+        for cred in credentials
+            .iter()
+            .filter(|c| c.status != CredentialStatus::Approved)
+        {
+            for client in clients
+                .values()
+                .filter(|c| c.credential.as_deref() == Some(&cred.name))
+            {
+                tracing::info!(
+                    "disconnecting {}: credential is {:?}",
+                    client.name,
+                    cred.status
+                );
+                client.kick.cancel();
+            }
+        }
+    }
+
+    /// Sends the current shared secret key to clients that authenticated
+    /// with an older one.
+    pub async fn push_rotated_secret(&self) {
+        let current = match self.secrets.load() {
+            Ok(secrets) => secrets.current,
+            Err(e) => {
+                tracing::warn!("failed to read secret key: {}", e);
+                return;
+            }
+        };
+
+        let mut clients = self.clients.write().await;
+        for client in clients.values_mut() {
+            if let Some(secret) = &client.shared_secret
+                && *secret != current
+                && client.supports(features::SECRET_UPDATE)
+            {
+                let (wrapped_key, hmac) = remoshot_common::wrap_secret(
+                    secret,
+                    &client.server_nonce,
+                    &client.client_nonce,
+                    &current,
+                );
+                let msg = remoshot_common::ServerMessage::SecretUpdate { wrapped_key, hmac };
+                if client.tx.send(serde_json::to_string(&msg).unwrap()).is_ok() {
+                    tracing::info!("sent rotated secret key to {}", client.name);
+                    client.shared_secret = Some(current.clone());
+                }
+            }
+        }
+    }
+
+    /// Tells every client to reconnect after `reconnect_after` and drops the
+    /// connections.
+    pub async fn disconnect_all(&self, reconnect_after: Duration) {
+        let msg = serde_json::to_string(&remoshot_common::ServerMessage::Shutdown {
+            reconnect_after_ms: reconnect_after.as_millis() as u64,
         })
         .unwrap();
-
-        let mut count = 0;
+        let clients = self.clients.read().await;
         for client in clients.values() {
-            if client.tx.send(msg.clone()).is_ok() {
-                count += 1;
+            if client.supports(features::SHUTDOWN) {
+                let _ = client.tx.send(msg.clone());
             }
+            client.kick.cancel();
         }
-        count
     }
 
-    pub async fn store_image(&self, path: PathBuf) {
-        let mut images = self.stored_images.lock().await;
-        images.push(StoredImage {
-            path,
-            created_at: Utc::now(),
+    /// Waits until every request that is still collecting responses has
+    /// finished.
+    pub async fn wait_for_pending_requests(&self) {
+        let mut waiting = Vec::new();
+        for pending in self.pending_requests.read().await.values() {
+            let req = pending.lock().await;
+            if !req.is_finished() {
+                waiting.push(req.subscribe());
+            }
+        }
+        for mut done in waiting {
+            let _ = done.wait_for(|done| *done).await;
+        }
+    }
+
+    /// Registers a new request and sends it to the given clients.
+    pub async fn start_request(
+        &self,
+        request_id: &str,
+        targets: &[usize],
+        options: &CaptureOptions,
+    ) -> Arc<Mutex<PendingRequest>> {
+        let pending = Arc::new(Mutex::new(PendingRequest::new(HashMap::new())));
+
+        // Hold the request until its targets are known so that early
+        // responses cannot complete it prematurely.
+        let mut req = pending.lock().await;
+        {
+            let mut requests = self.pending_requests.write().await;
+            requests.insert(request_id.to_string(), pending.clone());
+        }
+        req.targets = self
+            .send_screenshot_request(request_id, targets, options)
+            .await;
+        if req.targets.is_empty() {
+            req.finish();
+        }
+        drop(req);
+
+        pending
+    }
+
+    /// Forgets finished requests older than `ttl`.
+    pub async fn prune_finished_requests(&self, ttl: Duration) {
+        let mut requests = self.pending_requests.write().await;
+        let mut expired = Vec::new();
+        for (id, pending) in requests.iter() {
+            let req = pending.lock().await;
+            if req.finished_at.is_some_and(|t| t.elapsed() > ttl) {
+                expired.push(id.clone());
+            }
+        }
+        for id in expired {
+            requests.remove(&id);
+        }
+    }
+
+    /// Marks a connection as disconnected in every request still waiting
+    /// for it.
+    pub async fn fail_pending_for(&self, client_id: usize, client_name: &str) {
+        let requests = self.pending_requests.read().await;
+        for pending in requests.values() {
+            let mut req = pending.lock().await;
+            if req.targets.contains_key(&client_id) && !req.received.contains_key(client_name) {
+                req.record(
+                    client_name,
+                    ClientStatus::Disconnected,
+                    Vec::new(),
+                    Vec::new(),
+                );
+            }
+        }
+    }
+
+    /// The request `request_id` if it is waiting for connection `client_id`,
+    /// along with the server's own copy of its ID for use in file names.
+    pub async fn pending_for(
+        &self,
+        request_id: &str,
+        client_id: usize,
+    ) -> Option<(String, Arc<Mutex<PendingRequest>>)> {
+        let (id, pending) = {
+            let requests = self.pending_requests.read().await;
+            let (id, pending) = requests.get_key_value(request_id)?;
+            (id.clone(), pending.clone())
+        };
+        let targeted = pending.lock().await.targets.contains_key(&client_id);
+        targeted.then_some((id, pending))
+    }
+
+    pub async fn select_clients(&self, selectors: &[String]) -> Result<Vec<usize>, Vec<String>> {
+        let clients = self.clients.read().await;
+        if selectors.is_empty() {
+            return Ok(clients.keys().copied().collect());
+        }
+
+        let mut selected = Vec::new();
+        let mut unknown = Vec::new();
+        for selector in selectors {
+            let pattern = WildMatch::new(selector);
+            let mut matched = false;
+            for (id, client) in clients.iter() {
+                if pattern.matches(&client.name) {
+                    matched = true;
+                    if !selected.contains(id) {
+                        selected.push(*id);
+                    }
+                }
+            }
+            if !matched {
+                unknown.push(selector.clone());
+            }
+        }
+
+        if unknown.is_empty() {
+            Ok(selected)
+        } else {
+            Err(unknown)
+        }
+    }
+
+    pub async fn send_screenshot_request(
+        &self,
+        request_id: &str,
+        targets: &[usize],
+        options: &CaptureOptions,
+    ) -> HashMap<usize, String> {
+        let clients = self.clients.read().await;
+        let recipients: Vec<(usize, &ConnectedClient)> = targets
+            .iter()
+            .filter_map(|id| clients.get(id).map(|c| (*id, c)))
+            .collect();
+        self.events.publish(ServerEvent::RequestStarted {
+            request_id: request_id.to_string(),
+            clients: recipients.iter().map(|(_, c)| c.name.clone()).collect(),
         });
+
+        let mut sent = HashMap::new();
+        for (id, client) in recipients {
+            let msg = serde_json::to_string(&remoshot_common::ServerMessage::ScreenshotRequest {
+                request_id: request_id.to_string(),
+                options: client.adapt_options(options),
+            })
+            .unwrap();
+            if client.tx.send(msg).is_ok() {
+                sent.insert(id, client.name.clone());
+            }
+        }
+        sent
     }
 }
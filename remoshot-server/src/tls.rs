@@ -0,0 +1,104 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use axum::Router;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use sha2::{Digest, Sha256};
+use tokio_util::sync::CancellationToken;
+
+const SELF_SIGNED_CERT: &str = "self-signed.crt";
+const SELF_SIGNED_KEY: &str = "self-signed.key";
+
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Returns the self-signed certificate in `dir`, generating it on first use
+/// so that its fingerprint stays the same across restarts. It is issued for
+/// localhost, this machine's host name and `extra_names`.
+pub fn self_signed(dir: &Path, extra_names: &[String]) -> io::Result<TlsFiles> {
+    let files = TlsFiles {
+        cert: dir.join(SELF_SIGNED_CERT),
+        key: dir.join(SELF_SIGNED_KEY),
+    };
+    if files.cert.exists() && files.key.exists() {
+        return Ok(files);
+    }
+
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let host = gethostname::gethostname().to_string_lossy().into_owned();
+    for name in std::iter::once(host).chain(extra_names.iter().cloned()) {
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    std::fs::write(&files.cert, certified.cert.pem())?;
+    crate::secret::write_private(&files.key, certified.key_pair.serialize_pem())?;
+    tracing::info!("generated self-signed certificate {:?}", files.cert);
+
+    Ok(files)
+}
+
+/// SHA-256 fingerprint of the first certificate in `cert`, as colon
+/// separated upper-case hex.
+pub fn fingerprint(cert: &Path) -> io::Result<String> {
+    let der = CertificateDer::pem_file_iter(cert)
+        .map_err(io::Error::other)?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no certificate found"))?
+        .map_err(io::Error::other)?;
+
+    Ok(Sha256::digest(der.as_ref())
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+pub async fn load(files: &TlsFiles) -> io::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&files.cert, &files.key).await
+}
+
+/// Serves `app` on `addr`, over TLS when a configuration is given. Once
+/// `shutdown` is cancelled no new connections are accepted and this returns
+/// when the open ones are done.
+pub async fn serve(
+    addr: SocketAddr,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    shutdown: CancellationToken,
+) {
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(config) => {
+            let handle = Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown.cancelled().await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+            axum_server::bind_rustls(addr, config)
+                .handle(handle)
+                .serve(service)
+                .await
+                .unwrap()
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+                .unwrap();
+        }
+    }
+}
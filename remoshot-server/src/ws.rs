@@ -1,34 +1,60 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::Router;
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
-use axum::response::IntoResponse;
+use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
+use axum_server::tls_rustls::RustlsConfig;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use remoshot_common::{AuthRejectReason, Capabilities, Hello};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::state::AppState;
+use crate::credentials::CredentialStatus;
+use crate::events::ServerEvent;
+use crate::index::{self, StoredImage};
+use crate::state::{AppState, CaptureError, ClientStatus, ConnectedClient, ImageDetails};
+use crate::tls;
 
-pub async fn run_ws_server(addr: SocketAddr, state: Arc<AppState>) {
+const MAX_NAME_LEN: usize = 64;
+
+/// How long a closing connection may take to deliver its queued messages.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn run_ws_server(addr: SocketAddr, state: Arc<AppState>, tls: Option<RustlsConfig>) {
+    let shutdown = state.shutdown.clone();
     let app = Router::new()
         .route("/ws", get(ws_handler))
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    tls::serve(addr, app, tls, shutdown).await;
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    if state.shutdown.is_cancelled() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let connections = state.connections.clone();
+    ws.on_upgrade(move |socket| connections.track_future(handle_socket(socket, state, addr)))
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, addr: SocketAddr) {
     let (mut ws_tx, mut ws_rx) = socket.split();
     let client_id = state.next_id().await;
 
+    let hello = Hello::new(env!("CARGO_PKG_VERSION"), Capabilities::all());
+    send_json(&mut ws_tx, &remoshot_common::ServerMessage::Hello(hello)).await;
+
     let nonce = Uuid::new_v4().to_string();
     let challenge = remoshot_common::ServerMessage::AuthChallenge {
         nonce: nonce.clone(),
@@ -42,20 +68,147 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
         return;
     }
 
-    let client_name = loop {
+    let mut client_hello = None;
+    let (client_name, machine_id, client_nonce, key, shared) = loop {
         match ws_rx.next().await {
             Some(Ok(Message::Text(text))) => {
-                if let Ok(remoshot_common::ClientMessage::AuthResponse { name, hmac }) =
-                    serde_json::from_str(&text)
-                {
-                    if remoshot_common::verify_hmac(&state.secret_key, &nonce, &hmac) {
-                        break name;
-                    } else {
-                        tracing::warn!("invalid HMAC from client {}", name);
+                match serde_json::from_str::<remoshot_common::ClientMessage>(&text) {
+                    Ok(remoshot_common::ClientMessage::Hello(hello)) => {
+                        if !hello.is_compatible() {
+                            tracing::warn!(
+                                "rejected client {} speaking protocol version {}",
+                                addr,
+                                hello.protocol_version
+                            );
+                            send_json(
+                                &mut ws_tx,
+                                &remoshot_common::ServerMessage::AuthRejected {
+                                    reason: AuthRejectReason::UnsupportedVersion,
+                                },
+                            )
+                            .await;
+                            return;
+                        }
+                        client_hello = Some(hello);
+                    }
+                    // Clients that send no hello speak protocol version 1 and
+                    // answer the bare nonce, which proves nothing to them.
+                    Ok(remoshot_common::ClientMessage::AuthResponse {
+                        name,
+                        hmac,
+                        client_nonce: None,
+                        machine_id,
+                    }) if client_hello.is_none() && state.config.allow_legacy_clients => {
+                        match authenticate(
+                            &state,
+                            &name,
+                            &nonce,
+                            &hmac,
+                            remoshot_common::verify_hmac,
+                        ) {
+                            Ok((key, shared)) => {
+                                tracing::warn!(
+                                    "client {} speaks protocol version 1 and cannot verify this server",
+                                    name
+                                );
+                                client_hello = Some(remoshot_common::Hello::legacy());
+                                break (name, machine_id, None, key, shared);
+                            }
+                            Err(reason) => {
+                                tracing::warn!("rejected client {}: {:?}", name, reason);
+                                send_json(
+                                    &mut ws_tx,
+                                    &remoshot_common::ServerMessage::AuthRejected { reason },
+                                )
+                                .await;
+                                return;
+                            }
+                        }
+                    }
+                    Ok(
+                        remoshot_common::ClientMessage::AuthResponse { ref name, .. }
+                        | remoshot_common::ClientMessage::Enroll { ref name, .. },
+                    ) if client_hello.is_none() => {
+                        tracing::warn!("rejected client {}: it sent no hello", name);
+                        send_json(
+                            &mut ws_tx,
+                            &remoshot_common::ServerMessage::AuthRejected {
+                                reason: AuthRejectReason::UnsupportedVersion,
+                            },
+                        )
+                        .await;
                         return;
                     }
+                    Ok(remoshot_common::ClientMessage::AuthResponse {
+                        name,
+                        client_nonce: None,
+                        ..
+                    }) => {
+                        tracing::warn!(
+                            "rejected client {}: it predates mutual authentication",
+                            name
+                        );
+                        send_json(
+                            &mut ws_tx,
+                            &remoshot_common::ServerMessage::AuthRejected {
+                                reason: AuthRejectReason::UnsupportedVersion,
+                            },
+                        )
+                        .await;
+                        return;
+                    }
+                    Ok(remoshot_common::ClientMessage::AuthResponse {
+                        name,
+                        hmac,
+                        client_nonce: Some(client_nonce),
+                        machine_id,
+                    }) => match authenticate(
+                        &state,
+                        &name,
+                        &nonce,
+                        &hmac,
+                        remoshot_common::verify_client_hmac,
+                    ) {
+                        Ok((key, shared)) => {
+                            break (name, machine_id, Some(client_nonce), key, shared);
+                        }
+                        Err(reason) => {
+                            tracing::warn!("rejected client {}: {:?}", name, reason);
+                            send_json(
+                                &mut ws_tx,
+                                &remoshot_common::ServerMessage::AuthRejected { reason },
+                            )
+                            .await;
+                            return;
+                        }
+                    },
+                    Ok(remoshot_common::ClientMessage::Enroll { name, token }) => {
+                        let enrolled = match validate_name(&name) {
+                            Ok(()) => state.credentials.enroll(&name, &token),
+                            Err(reason) => Ok(Err(reason)),
+                        };
+                        let reply = match enrolled {
+                            Ok(Ok(key)) => {
+                                tracing::info!("client {} enrolled, awaiting approval", name);
+                                state
+                                    .events
+                                    .publish(ServerEvent::ClientEnrolled { name: name.clone() });
+                                remoshot_common::ServerMessage::Enrolled { key }
+                            }
+                            Ok(Err(reason)) => {
+                                tracing::warn!("rejected enrollment of {}: {:?}", name, reason);
+                                remoshot_common::ServerMessage::AuthRejected { reason }
+                            }
+                            Err(e) => {
+                                tracing::error!("failed to store client credentials: {}", e);
+                                return;
+                            }
+                        };
+                        send_json(&mut ws_tx, &reply).await;
+                        return;
+                    }
+                    _ => tracing::warn!("expected AuthResponse message, got: {}", text),
                 }
-                tracing::warn!("expected AuthResponse message, got: {}", text);
             }
             Some(Ok(Message::Close(_))) | None => {
                 return;
@@ -64,16 +217,76 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
         }
     };
 
-    tracing::info!("client authenticated: {} (id={})", client_name, client_id);
+    // Authenticating requires a hello, see above.
+    let Some(client_hello) = client_hello else {
+        return;
+    };
 
     let (tx, mut rx) = mpsc::unbounded_channel::<String>();
     let (pong_tx, mut pong_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let kick = CancellationToken::new();
 
-    state
-        .register_client(client_id, client_name.clone(), tx)
-        .await;
+    let now = chrono::Utc::now();
+    let client = ConnectedClient {
+        name: client_name.clone(),
+        machine_id,
+        protocol_version: client_hello.protocol_version,
+        app_version: client_hello.app_version.clone(),
+        capabilities: client_hello.capabilities,
+        info: None,
+        addr,
+        connected_at: now,
+        last_message_at: now,
+        screenshots_served: 0,
+        tx,
+        kick: kick.clone(),
+        shared_secret: shared.then(|| key.clone()),
+        server_nonce: nonce.clone(),
+        client_nonce: client_nonce.clone().unwrap_or_default(),
+        credential: (!shared).then(|| client_name.clone()),
+    };
+    let client_name = match state.register_client(client_id, client).await {
+        Ok(name) => name,
+        Err(reason) => {
+            tracing::warn!("rejected client {}: {:?}", client_name, reason);
+            send_json(
+                &mut ws_tx,
+                &remoshot_common::ServerMessage::AuthRejected { reason },
+            )
+            .await;
+            return;
+        }
+    };
+
+    if let Some(client_nonce) = &client_nonce {
+        let confirm = remoshot_common::ServerMessage::AuthConfirm {
+            hmac: remoshot_common::compute_server_hmac(&key, &nonce, client_nonce),
+        };
+        let confirm_msg = serde_json::to_string(&confirm).unwrap();
+        if ws_tx.send(Message::Text(confirm_msg.into())).await.is_err() {
+            state.unregister_client(client_id).await;
+            return;
+        }
+    }
+
+    tracing::info!(
+        "client authenticated: {} (id={}, app version {}, protocol version {})",
+        client_name,
+        client_id,
+        client_hello.app_version,
+        client_hello.protocol_version
+    );
+    if shared {
+        // Hands out the current secret if the client is still on the
+        // previous one.
+        state.push_rotated_secret().await;
+    }
+    state.events.publish(ServerEvent::ClientConnected {
+        client_id,
+        name: client_name.clone(),
+    });
 
-    let send_task = tokio::spawn(async move {
+    let mut send_task = tokio::spawn(async move {
         loop {
             tokio::select! {
                 msg_opt = rx.recv() => {
@@ -98,18 +311,49 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                 }
             }
         }
+        let _ = ws_tx.close().await;
     });
 
-    while let Some(msg_result) = ws_rx.next().await {
+    loop {
+        let msg_result = tokio::select! {
+            _ = kick.cancelled() => break,
+            msg = ws_rx.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        state.touch_client(client_id).await;
         match msg_result {
             Ok(Message::Text(text)) => {
                 match serde_json::from_str::<remoshot_common::ClientMessage>(&text) {
-                    Ok(remoshot_common::ClientMessage::AuthResponse { .. }) => {
+                    Ok(
+                        remoshot_common::ClientMessage::Hello(_)
+                        | remoshot_common::ClientMessage::AuthResponse { .. }
+                        | remoshot_common::ClientMessage::Enroll { .. },
+                    ) => {
                         tracing::warn!("duplicate auth from {}", client_name);
                     }
+                    Ok(remoshot_common::ClientMessage::ClientInfo(info)) => {
+                        handle_client_info(&state, client_id, &client_name, info).await;
+                    }
                     Ok(remoshot_common::ClientMessage::ScreenshotResponse { .. }) => {
                         tracing::warn!("unexpected JSON screenshot response from {}", client_name);
                     }
+                    Ok(remoshot_common::ClientMessage::ScreenshotError {
+                        request_id,
+                        reason,
+                        detail,
+                    }) => {
+                        handle_screenshot_error(
+                            &state,
+                            client_id,
+                            &client_name,
+                            &request_id,
+                            reason,
+                            detail,
+                        )
+                        .await;
+                    }
                     Err(e) => {
                         tracing::warn!("invalid JSON message from {}: {}", client_name, e);
                     }
@@ -120,11 +364,41 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                     Ok(remoshot_common::ClientMessage::ScreenshotResponse {
                         request_id,
                         screenshots,
+                        errors,
                     }) => {
-                        handle_screenshot_response(&state, &client_name, &request_id, screenshots)
-                            .await;
+                        handle_screenshot_response(
+                            &state,
+                            client_id,
+                            &client_name,
+                            &request_id,
+                            screenshots,
+                            errors,
+                        )
+                        .await;
+                    }
+                    Ok(remoshot_common::ClientMessage::ScreenshotError {
+                        request_id,
+                        reason,
+                        detail,
+                    }) => {
+                        handle_screenshot_error(
+                            &state,
+                            client_id,
+                            &client_name,
+                            &request_id,
+                            reason,
+                            detail,
+                        )
+                        .await;
+                    }
+                    Ok(remoshot_common::ClientMessage::ClientInfo(info)) => {
+                        handle_client_info(&state, client_id, &client_name, info).await;
                     }
-                    Ok(remoshot_common::ClientMessage::AuthResponse { .. }) => {
+                    Ok(
+                        remoshot_common::ClientMessage::Hello(_)
+                        | remoshot_common::ClientMessage::AuthResponse { .. }
+                        | remoshot_common::ClientMessage::Enroll { .. },
+                    ) => {
                         tracing::warn!("unexpected MessagePack auth from {}", client_name);
                     }
                     Err(e) => {
@@ -142,15 +416,105 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
     tracing::info!("client disconnected: {} (id={})", client_name, client_id);
     state.unregister_client(client_id).await;
-    send_task.abort();
+    state.fail_pending_for(client_id, &client_name).await;
+    state.events.publish(ServerEvent::ClientDisconnected {
+        client_id,
+        name: client_name.clone(),
+    });
+
+    // Unregistering dropped the sender, so the send task ends once it has
+    // delivered what is queued, such as the shutdown notice.
+    drop(pong_tx);
+    if tokio::time::timeout(FLUSH_TIMEOUT, &mut send_task)
+        .await
+        .is_err()
+    {
+        send_task.abort();
+    }
+}
+
+/// Verifies an auth response against the client's own key or, for clients
+/// that never enrolled, the current or previous shared secret. Returns the
+/// key that matched and whether it is a shared secret. `verify` checks the
+/// proof the client's protocol version calls for.
+fn authenticate(
+    state: &AppState,
+    name: &str,
+    nonce: &str,
+    hmac: &str,
+    verify: fn(&str, &str, &str) -> bool,
+) -> Result<(String, bool), AuthRejectReason> {
+    validate_name(name)?;
+    let credential = state.credentials.get(name).map_err(|e| {
+        tracing::error!("failed to read client credentials: {}", e);
+        AuthRejectReason::InvalidCredentials
+    })?;
+    if let Some(cred) = credential {
+        if !verify(&cred.key, nonce, hmac) {
+            return Err(AuthRejectReason::InvalidCredentials);
+        }
+        return match cred.status {
+            CredentialStatus::Pending => Err(AuthRejectReason::PendingApproval),
+            CredentialStatus::Revoked => Err(AuthRejectReason::Revoked),
+            CredentialStatus::Approved => Ok((cred.key, false)),
+        };
+    }
+
+    if state.config.require_enrollment {
+        return Err(AuthRejectReason::InvalidCredentials);
+    }
+    let secrets = state.secrets.load().map_err(|e| {
+        tracing::error!("failed to read secret key: {}", e);
+        AuthRejectReason::InvalidCredentials
+    })?;
+    [Some(secrets.current), secrets.previous]
+        .into_iter()
+        .flatten()
+        .find(|key| verify(key, nonce, hmac))
+        .map(|key| (key, true))
+        .ok_or(AuthRejectReason::InvalidCredentials)
+}
+
+/// Names are shown in the API, used as credential keys and, sanitized, in
+/// image filenames.
+fn validate_name(name: &str) -> Result<(), AuthRejectReason> {
+    if name.trim().is_empty()
+        || name.chars().count() > MAX_NAME_LEN
+        || name.chars().any(char::is_control)
+    {
+        return Err(AuthRejectReason::InvalidName);
+    }
+    Ok(())
+}
+
+async fn send_json(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    msg: &remoshot_common::ServerMessage,
+) {
+    let text = serde_json::to_string(msg).unwrap();
+    let _ = ws_tx.send(Message::Text(text.into())).await;
 }
 
 async fn handle_screenshot_response(
     state: &Arc<AppState>,
+    client_id: usize,
     client_name: &str,
     request_id: &str,
     screenshots: Vec<remoshot_common::ScreenshotData>,
+    monitor_errors: Vec<remoshot_common::MonitorError>,
 ) {
+    // The ID comes from the client; only the server's own copy of a request
+    // that targeted this connection makes it into file names.
+    let Some((request_id, pending)) = state.pending_for(request_id, client_id).await else {
+        tracing::warn!(
+            "ignoring screenshot response from {} for unknown request {:?}",
+            client_name,
+            request_id
+        );
+        return;
+    };
+    let request_id = request_id.as_str();
+
     tracing::info!(
         "received screenshot response from {} for request {}: {} images",
         client_name,
@@ -158,41 +522,145 @@ async fn handle_screenshot_response(
         screenshots.len()
     );
 
-    let mut image_paths = Vec::new();
+    let mut errors = Vec::new();
+    for err in monitor_errors {
+        tracing::warn!(
+            "client {} failed to capture monitor {} for request {}: {:?}: {}",
+            client_name,
+            err.monitor,
+            request_id,
+            err.reason,
+            err.detail
+        );
+        errors.push(CaptureError {
+            monitor: Some(err.monitor),
+            reason: err.reason,
+            detail: err.detail,
+        });
+    }
+
+    let mut stored = Vec::new();
+    let mut write_failed = false;
 
     for shot in &screenshots {
-        let filename = format!(
-            "{}_{}_{}_{}.jpg",
+        let stitched = shot.meta.as_ref().is_some_and(|m| !m.stitched.is_empty());
+        let filename = index::image_filename(
             request_id,
             client_name,
             shot.monitor,
-            chrono::Utc::now().timestamp_millis()
+            stitched,
+            chrono::Utc::now().timestamp_millis(),
+            shot.format,
         );
-        let path = state.image_dir.join(&filename);
+        let path = state.config.image_dir.join(&filename);
 
         if let Err(e) = tokio::fs::write(&path, &shot.data).await {
             tracing::warn!("failed to write image {}: {}", filename, e);
+            write_failed = true;
             continue;
         }
 
-        let url_path = format!("/images/{}", filename);
-        image_paths.push(url_path);
-        state.store_image(path).await;
+        let image = StoredImage {
+            request_id: request_id.to_string(),
+            client: client_name.to_string(),
+            monitor: shot.monitor,
+            stitched,
+            filename,
+            size: shot.data.len() as u64,
+            created_at: chrono::Utc::now(),
+            meta: shot.meta.clone(),
+        };
+        let url = state.url_signer.sign(&image.filename);
+        stored.push(ImageDetails {
+            url: url.clone(),
+            monitor: shot.monitor,
+            format: shot.format,
+            size: image.size,
+            meta: shot.meta.clone(),
+        });
+        state.events.publish(ServerEvent::ImageStored {
+            request_id: image.request_id.clone(),
+            client: image.client.clone(),
+            monitor: image.monitor,
+            stitched: image.stitched,
+            url,
+        });
+        state.images.insert(image).await;
     }
 
-    let pending = {
-        let requests = state.pending_requests.read().await;
-        requests.get(request_id).cloned()
+    state
+        .record_screenshots_served(client_id, stored.len())
+        .await;
+
+    let status = if screenshots.is_empty() {
+        ClientStatus::CaptureFailed
+    } else if write_failed {
+        ClientStatus::WriteFailed
+    } else {
+        ClientStatus::Ok
+    };
+
+    let mut req = pending.lock().await;
+    req.record(client_name, status, stored, errors);
+}
+
+async fn handle_screenshot_error(
+    state: &Arc<AppState>,
+    client_id: usize,
+    client_name: &str,
+    request_id: &str,
+    reason: remoshot_common::CaptureErrorReason,
+    detail: String,
+) {
+    let Some((request_id, pending)) = state.pending_for(request_id, client_id).await else {
+        tracing::warn!(
+            "ignoring screenshot error from {} for unknown request {:?}",
+            client_name,
+            request_id
+        );
+        return;
     };
 
-    if let Some(pending) = pending {
-        let mut req = pending.lock().await;
-        req.received.insert(client_name.to_string(), image_paths);
+    tracing::warn!(
+        "client {} failed to capture for request {}: {:?}: {}",
+        client_name,
+        request_id,
+        reason,
+        detail
+    );
 
-        if req.received.len() >= req.expected
-            && let Some(notify) = req.notify.take()
-        {
-            let _ = notify.send(req.received.clone());
-        }
-    }
+    let mut req = pending.lock().await;
+    let error = CaptureError {
+        monitor: None,
+        reason,
+        detail,
+    };
+    req.record(
+        client_name,
+        ClientStatus::CaptureFailed,
+        Vec::new(),
+        vec![error],
+    );
+}
+
+async fn handle_client_info(
+    state: &Arc<AppState>,
+    client_id: usize,
+    client_name: &str,
+    info: remoshot_common::ClientInfo,
+) {
+    tracing::info!(
+        "client {} runs {} {} on {} with {} monitors",
+        client_name,
+        info.os,
+        info.app_version,
+        info.hostname,
+        info.monitors.len()
+    );
+    state.set_client_info(client_id, info.clone()).await;
+    state.events.publish(ServerEvent::ClientInfo {
+        client_id,
+        name: client_name.to_string(),
+        info,
+    });
 }